@@ -1,65 +1,848 @@
 //! mot --- create Octatrack metadata files and handle transfers to
 //! device in USB mode.
 
-use ot::{OTWriter, TrigQuantType, StretchType};
+use ot::{OTWriter, OTData, TrigQuantType, StretchType, LoopType};
+use ot::chain::{ChainWriter, Length};
+use ot::inspect::Inspector;
+use clap::{Args, Parser, Subcommand};
 use hound::WavReader;
-use std::path::Path;
-use std::{fs, env, io};
-
-fn auto_gen_dir<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
-  let mut writer = OTWriter::new("")
-    .trig_quant_type(TrigQuantType::Pattern);
-
-  let path = path.as_ref();
-  if path.is_file() {
-    if let Some(ext) = &path.extension() {
-      match ext.to_str().unwrap() {
-	"wav" => {
-	  if path.file_name().unwrap().to_str().unwrap().starts_with("._") {
-	    println!("skipping temp_file: {}", path.display());
-	  } else if !path.with_extension("ot").exists() {
-	    println!("generating ot for file: {}", path.display());
-	    let mut stretch_type = StretchType::Normal;
-	    let mut tempo: f32 = path.file_name().unwrap().to_str().unwrap()[..5]
-	      .parse().expect(format!("failed to parse tempo from file: {}", path.display()).as_str());
-	    // tempo of 0.00 means this is a freerun track. set the
-	    // tempo to 120 and turn off timestretch.
-	    if tempo.eq(&0.) {
-	      tempo = 120.;
-	      stretch_type = StretchType::Off;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::{fs, io, thread};
+
+#[derive(Parser)]
+#[command(name = "mot", about = "create Octatrack metadata files")]
+struct Cli {
+  #[command(subcommand)]
+  cmd: Command,
+  /// print what would be done without writing any files
+  #[arg(long, global = true)]
+  dry_run: bool,
+  /// print results as JSON
+  #[arg(long, global = true)]
+  json: bool,
+  /// print a line for every file, not just failures
+  #[arg(short, long, global = true)]
+  verbose: bool,
+  /// write a JSON report of the results to FILE
+  #[arg(long, global = true, value_name = "FILE")]
+  report: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// generate .ot files for wav and aiff files, taking the tempo from
+  /// the file name (140.00_ARTIST_TRACK.wav)
+  Gen {
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+    #[command(flatten)]
+    ot: OtArgs,
+    #[command(flatten)]
+    walk: WalkArgs,
+  },
+  /// print the contents of .ot files
+  Info {
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// sample rate used to show positions as time (default: from the
+    /// matching audio file, or 44100)
+    #[arg(long)]
+    sample_rate: Option<u32>,
+  },
+  /// show the differences between two .ot files
+  Diff {
+    a: PathBuf,
+    b: PathBuf,
+    /// sample rate used to show positions as time (default: from the
+    /// matching audio file, or 44100)
+    #[arg(long)]
+    sample_rate: Option<u32>,
+  },
+  /// combine wav files into a sample chain with a matching .ot file
+  Chain {
+    output: PathBuf,
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+    #[command(flatten)]
+    ot: OtArgs,
+    /// trim leading and trailing silence below this level (dBFS)
+    #[arg(long, allow_negative_numbers = true)]
+    trim: Option<f32>,
+    /// fade slice edges (ms)
+    #[arg(long)]
+    fade: Option<f32>,
+    /// pad slices to a multiple of LENGTH (N, Nst or Nbar)
+    #[arg(long)]
+    pad: Option<Length>,
+    /// give every slice exactly LENGTH (N, Nst or Nbar)
+    #[arg(long)]
+    slot: Option<Length>,
+    #[command(flatten)]
+    walk: WalkArgs,
+  },
+  /// create an .ot file with evenly spaced slices for a wav file
+  Slice {
+    file: PathBuf,
+    #[command(flatten)]
+    ot: OtArgs,
+    /// number of slices, 1 to 64
+    #[arg(long, conflicts_with = "length", value_parser = clap::value_parser!(u32).range(1..=64))]
+    count: Option<u32>,
+    /// length of each slice (N, Nst or Nbar)
+    #[arg(long)]
+    length: Option<Length>,
+  },
+  /// check .ot files for errors
+  Check {
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+    #[command(flatten)]
+    walk: WalkArgs,
+  },
+  /// change settings of existing .ot files
+  Edit {
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    #[command(flatten)]
+    ot: OtArgs,
+  },
+}
+
+#[derive(Args)]
+struct OtArgs {
+  /// off, normal or beat
+  #[arg(long)]
+  stretch: Option<StretchType>,
+  /// off, loop or pingpong
+  #[arg(long = "loop")]
+  loop_type: Option<LoopType>,
+  /// direct, pattern or s1..s256
+  #[arg(long)]
+  quantize: Option<TrigQuantType>,
+  /// gain in half-dB steps, -48 to 48 (-24 to +24 dB)
+  #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-48..=48))]
+  gain: Option<i32>,
+  /// tempo in BPM
+  #[arg(long)]
+  tempo: Option<f32>,
+}
+
+impl OtArgs {
+  /// Apply the given options to WRITER, leaving the rest untouched.
+  fn apply(&self, mut writer: OTWriter) -> OTWriter {
+    if let Some(s) = self.stretch {
+      writer = writer.stretch_type(s);
+    }
+    if let Some(l) = self.loop_type {
+      writer = writer.loop_type(l);
+    }
+    if let Some(q) = self.quantize {
+      writer = writer.trig_quant_type(q);
+    }
+    if let Some(g) = self.gain {
+      writer = writer.gain_db(g as f32 / 2.);
+    }
+    if let Some(t) = self.tempo {
+      writer = writer.tempo(t);
+    }
+    writer
+  }
+}
+
+#[derive(Args)]
+struct WalkArgs {
+  /// maximum depth of subdirectories to descend into
+  #[arg(long)]
+  depth: Option<usize>,
+  /// only include files matching GLOB
+  #[arg(long, value_name = "GLOB")]
+  include: Vec<glob::Pattern>,
+  /// exclude files matching GLOB
+  #[arg(long, value_name = "GLOB")]
+  exclude: Vec<glob::Pattern>,
+  /// number of worker threads (default: number of CPUs)
+  #[arg(short, long)]
+  jobs: Option<usize>,
+}
+
+/// A unit of work for `WalkArgs::run`.
+enum Job {
+  Dir(PathBuf, usize),
+  File(PathBuf),
+}
+
+/// Shared state of the worker threads: pending jobs and the number of
+/// jobs in progress.
+struct Queue {
+  state: Mutex<(Vec<Job>, usize)>,
+  ready: Condvar,
+}
+
+impl Queue {
+  /// Take the next job, or None once the queue is empty and no running
+  /// job can add more work.
+  fn pop(&self) -> Option<Job> {
+    let mut state = self.state.lock().unwrap();
+    loop {
+      if let Some(job) = state.0.pop() {
+	state.1 += 1;
+	return Some(job)
+      } else if state.1 == 0 {
+	self.ready.notify_all();
+	return None
+      }
+      state = self.ready.wait(state).unwrap();
+    }
+  }
+
+  fn push(&self, jobs: Vec<Job>) {
+    let mut state = self.state.lock().unwrap();
+    state.0.extend(jobs);
+    self.ready.notify_all();
+  }
+
+  fn done(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.1 -= 1;
+    self.ready.notify_all();
+  }
+}
+
+impl WalkArgs {
+  fn matches(&self, path: &Path) -> bool {
+    let m = |p: &glob::Pattern| p.matches_path(path)
+      || path.file_name().map(|n| p.matches(&n.to_string_lossy())).unwrap_or(false);
+    (self.include.is_empty() || self.include.iter().any(m)) && !self.exclude.iter().any(m)
+  }
+
+  /// Collect all files with one of the extensions EXTS under PATHS.
+  fn collect(&self, paths: &[PathBuf], exts: &[&str]) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for path in paths {
+      self.walk(path, exts, 0, &mut files)?;
+    }
+    Ok(files)
+  }
+
+  /// Walk PATHS and call F on all files with one of the extensions
+  /// EXTS, spreading both over worker threads. Reports are returned in
+  /// file order.
+  fn run<F>(&self, paths: &[PathBuf], exts: &[&str], f: F) -> io::Result<Vec<Report>>
+  where F: Fn(&Path) -> Report + Sync {
+    let mut jobs = vec![];
+    for path in paths {
+      if path.is_file() {
+	if self.is_match(path, exts) {
+	  jobs.push(Job::File(path.to_path_buf()));
+	}
+      } else if path.is_dir() {
+	jobs.push(Job::Dir(path.to_path_buf(), 0));
+      } else {
+	return Err(io::Error::new(io::ErrorKind::NotFound, format!("files not found: {}", path.display())))
+      }
+    }
+    let pb = ProgressBar::new(jobs.iter().filter(|j| matches!(j, Job::File(_))).count() as u64)
+      .with_style(ProgressStyle::default_bar().template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {wide_msg}")
+		  .unwrap()
+		  .progress_chars("#>-"));
+    let queue = Queue { state: Mutex::new((jobs, 0)), ready: Condvar::new() };
+    let reports = Mutex::new(vec![]);
+    let workers = self.jobs.unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1)).max(1);
+
+    thread::scope(|scope| {
+      for _ in 0..workers {
+	scope.spawn(|| {
+	  while let Some(job) = queue.pop() {
+	    match job {
+	      Job::File(path) => {
+		pb.set_message(path.display().to_string());
+		let report = f(&path);
+		reports.lock().unwrap().push(report);
+		pb.inc(1);
+	      },
+	      Job::Dir(path, depth) => match self.read_dir(&path, exts, depth) {
+		Ok(jobs) => {
+		  pb.inc_length(jobs.iter().filter(|j| matches!(j, Job::File(_))).count() as u64);
+		  queue.push(jobs);
+		},
+		Err(e) => reports.lock().unwrap().push(Report::new(&path, "failed", e.to_string())),
+	      },
 	    }
-	    let mut wav = WavReader::open(&path).unwrap();
-	    let len: u32 = wav.samples::<i16>().count() as u32;
-	    let sample_rate = wav.spec().sample_rate;
-	    writer = writer.file_name(path.with_extension("ot"))
-	      .tempo(tempo)
-	      .sample_rate(sample_rate)
-	      .stretch_type(stretch_type);
-
-	    writer.write(len)?;
+	    queue.done();
 	  }
-	},
-	_ => (),
+	});
       }
-    }
-  } else if path.is_dir() {
+    });
+    pb.finish_and_clear();
+
+    let mut reports = reports.into_inner().unwrap();
+    reports.sort_by(|a, b| a.file.cmp(&b.file));
+    Ok(reports)
+  }
+
+  fn is_match(&self, path: &Path, exts: &[&str]) -> bool {
+    path.extension().map(|e| exts.iter().any(|ext| e.eq_ignore_ascii_case(ext))).unwrap_or(false) && self.matches(path)
+  }
+
+  /// List the jobs for the entries of directory PATH.
+  fn read_dir(&self, path: &Path, exts: &[&str], depth: usize) -> io::Result<Vec<Job>> {
+    let mut jobs = vec![];
     for f in fs::read_dir(path)? {
-      let f = f?;
-      let file = f.path();
-      auto_gen_dir(file)?
+      let f = f?.path();
+      if f.is_dir() {
+	if depth < self.depth.unwrap_or(usize::MAX) {
+	  jobs.push(Job::Dir(f, depth + 1));
+	}
+      } else if self.is_match(&f, exts) {
+	jobs.push(Job::File(f));
+      }
+    }
+    Ok(jobs)
+  }
+
+  fn walk(&self, path: &Path, exts: &[&str], depth: usize, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if path.is_file() {
+      if self.is_match(path, exts) {
+	files.push(path.to_path_buf());
+      }
+    } else if path.is_dir() {
+      if depth > self.depth.unwrap_or(usize::MAX) {
+	return Ok(())
+      }
+      let mut entries = fs::read_dir(path)?
+	.map(|f| f.map(|f| f.path()))
+	.collect::<io::Result<Vec<PathBuf>>>()?;
+      entries.sort();
+      for f in entries {
+	self.walk(&f, exts, depth + 1, files)?
+      }
+    } else {
+      return Err(io::Error::new(io::ErrorKind::NotFound, format!("files not found: {}", path.display())))
     }
+    Ok(())
+  }
+}
+
+/// The outcome of processing a single file.
+struct Report {
+  file: PathBuf,
+  status: &'static str,
+  message: String,
+}
+
+impl Report {
+  fn new(file: &Path, status: &'static str, message: impl Into<String>) -> Self {
+    Report { file: file.to_path_buf(), status, message: message.into() }
+  }
+}
+
+fn reports_json(reports: &[Report]) -> serde_json::Value {
+  reports.iter()
+    .map(|r| json!({"file": r.file, "status": r.status, "message": r.message}))
+    .collect()
+}
+
+fn print_reports(reports: &[Report], json: bool) {
+  if json {
+    println!("{}", reports_json(reports));
   } else {
-      return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "files not found"))
+    for r in reports {
+      if r.message.is_empty() {
+	println!("{}: {}", r.status, r.file.display());
+      } else {
+	println!("{}: {} ({})", r.status, r.file.display(), r.message);
+      }
+    }
+  }
+}
+
+/// Count REPORTS by status, and skipped files by reason.
+fn summary(reports: &[Report]) -> serde_json::Value {
+  let mut status: BTreeMap<&str, usize> = BTreeMap::new();
+  let mut skipped: BTreeMap<&str, usize> = BTreeMap::new();
+  for r in reports {
+    *status.entry(r.status).or_default() += 1;
+    if r.status == "skipped" {
+      *skipped.entry(r.message.as_str()).or_default() += 1;
+    }
+  }
+  json!({"total": reports.len(), "status": status, "skipped": skipped})
+}
+
+/// Print the results of a directory run. Unless VERBOSE, only failures
+/// are listed before the summary.
+fn print_run(cli: &Cli, reports: &[Report]) -> io::Result<()> {
+  let summary = summary(reports);
+  if let Some(file) = &cli.report {
+    fs::write(file, json!({"files": reports_json(reports), "summary": summary}).to_string())?;
   }
+  if cli.json {
+    println!("{}", json!({"files": reports_json(reports), "summary": summary}));
+    return Ok(())
+  }
+  let shown: Vec<&Report> = reports.iter().filter(|r| cli.verbose || r.status == "failed").collect();
+  for r in shown {
+    print_reports(std::slice::from_ref(r), false);
+  }
+  let status: Vec<String> = summary["status"].as_object().unwrap().iter()
+    .map(|(k, v)| match k.as_str() {
+      "skipped" => {
+	let reasons: Vec<String> = summary["skipped"].as_object().unwrap().iter()
+	  .map(|(k, v)| format!("{}: {}", k, v))
+	  .collect();
+	format!("{}: {} ({})", k, v, reasons.join(", "))
+      },
+      _ => format!("{}: {}", k, v),
+    })
+    .collect();
+  println!("{} files. {}", reports.len(), status.join(", "));
   Ok(())
 }
 
-fn main() -> io::Result<()> {
-  let args: Vec<String> = env::args().collect();
-  if let Some(path) = args.iter().skip(1).next() {
-    auto_gen_dir(path)
+/// Error out if any of REPORTS failed, so the exit status reflects it.
+fn check_failed(reports: &[Report]) -> io::Result<()> {
+  let failed = reports.iter().filter(|r| r.status == "failed").count();
+  if failed > 0 {
+    Err(io::Error::other(format!("{} of {} files failed", failed, reports.len())))
   } else {
-    println!("mot PATH");
     Ok(())
   }
 }
+
+/// Parse the tempo from the start of a file name, such as
+/// 140.00_ARTIST_TRACK.wav
+fn parse_tempo(path: &Path) -> io::Result<f32> {
+  let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+  let end = name.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(name.len());
+  name[..end].parse()
+    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "failed to parse tempo from file name"))
+}
+
+/// Extensions of the audio files .ot files are made for.
+const AUDIO_EXTENSIONS: [&str; 3] = ["wav", "aif", "aiff"];
+
+/// The audio file next to an .ot FILE, if there is one.
+fn audio_file(file: &Path) -> Option<PathBuf> {
+  AUDIO_EXTENSIONS.iter()
+    .flat_map(|ext| [ext.to_string(), ext.to_uppercase()])
+    .map(|ext| file.with_extension(ext))
+    .find(|f| f.is_file())
+}
+
+/// Length in frames and sample rate of a WAV or AIFF file.
+fn audio_info(path: &Path) -> io::Result<(u32, u32)> {
+  if path.extension().map(|e| e.eq_ignore_ascii_case("wav")).unwrap_or(false) {
+    wav_info(path)
+  } else {
+    aiff_info(path)
+  }
+}
+
+fn wav_info(path: &Path) -> io::Result<(u32, u32)> {
+  let wav = WavReader::open(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+  Ok((wav.duration(), wav.spec().sample_rate))
+}
+
+/// Length in frames and sample rate from the COMM chunk of an AIFF
+/// file.
+fn aiff_info(path: &Path) -> io::Result<(u32, u32)> {
+  let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", msg, path.display()));
+  let mut file = io::BufReader::new(fs::File::open(path)?);
+  let mut head = [0u8; 12];
+  file.read_exact(&mut head)?;
+  if &head[0..4] != b"FORM" || !(&head[8..12] == b"AIFF" || &head[8..12] == b"AIFC") {
+    return Err(invalid("not an AIFF file"))
+  }
+  loop {
+    let mut chunk = [0u8; 8];
+    file.read_exact(&mut chunk).map_err(|_| invalid("missing COMM chunk"))?;
+    let len = u32::from_be_bytes(chunk[4..8].try_into().unwrap());
+    if &chunk[0..4] == b"COMM" && len >= 18 {
+      let mut comm = [0u8; 18];
+      file.read_exact(&mut comm)?;
+      let frames = u32::from_be_bytes(comm[2..6].try_into().unwrap());
+      // the sample rate is an 80-bit extended float
+      let exp = (u16::from_be_bytes([comm[8], comm[9]]) & 0x7FFF) as i32 - 16383;
+      let mantissa = u64::from_be_bytes(comm[10..18].try_into().unwrap());
+      let rate = if (0..32).contains(&exp) { (mantissa >> (63 - exp)) as u32 } else { 0 };
+      return Ok((frames, rate))
+    }
+    // chunks are padded to an even length
+    file.seek_relative(len as i64 + (len & 1) as i64)?;
+  }
+}
+
+fn gen_file(path: &Path, args: &OtArgs, dry_run: bool) -> Report {
+  if path.file_name().unwrap().to_string_lossy().starts_with("._") {
+    return Report::new(path, "skipped", "temp file")
+  }
+  let ot_file = path.with_extension("ot");
+  if ot_file.exists() {
+    return Report::new(path, "skipped", "exists")
+  }
+  let result = (|| {
+    let mut stretch_type = StretchType::Normal;
+    let mut tempo = match args.tempo {
+      Some(t) => t,
+      None => parse_tempo(path)?,
+    };
+    // tempo of 0.00 means this is a freerun track. set the tempo to 120
+    // and turn off timestretch.
+    if tempo.eq(&0.) {
+      tempo = 120.;
+      stretch_type = StretchType::Off;
+    }
+    let (len, sample_rate) = audio_info(path)?;
+    let mut writer = OtArgs { tempo: Some(tempo), ..*args }.apply(
+      OTWriter::new(&ot_file)
+	.trig_quant_type(TrigQuantType::Pattern)
+	.stretch_type(stretch_type))
+      .sample_rate(sample_rate);
+    if !dry_run {
+      writer.write(len)?;
+    }
+    Ok::<_, io::Error>(())
+  })();
+  match result {
+    Ok(()) => Report::new(path, if dry_run { "dry-run" } else { "generated" }, ""),
+    Err(e) => Report::new(path, "failed", e.to_string()),
+  }
+}
+
+/// Decode an .ot FILE, taking the sample rate from SAMPLE_RATE or the
+/// matching audio file.
+fn inspect(file: &Path, sample_rate: Option<u32>) -> io::Result<(OTData, Inspector)> {
+  let data = OTData::decode(&fs::read(file)?)?;
+  let sample_rate = match sample_rate {
+    Some(n) => n,
+    None => audio_file(file).and_then(|f| audio_info(&f).ok()).map(|(_, n)| n).unwrap_or(44100),
+  };
+  Ok((data, Inspector::new(data, sample_rate)))
+}
+
+fn info(file: &Path, sample_rate: Option<u32>, json: bool) -> io::Result<()> {
+  let (data, inspector) = inspect(file, sample_rate)?;
+  if json {
+    let slices: Vec<_> = data.slices().iter()
+      .map(|s| json!({
+	"start": s.start(),
+	"end": s.end(),
+	"loop": s.loop_start(),
+	"start_time": inspector.time(s.start()),
+	"end_time": inspector.time(s.end()),
+      }))
+      .collect();
+    let fields: serde_json::Map<String, serde_json::Value> = inspector.fields().into_iter()
+      .map(|(k, v)| (k.to_string(), v.into()))
+      .collect();
+    println!("{}", json!({
+      "file": file,
+      "tempo": data.tempo(),
+      "trim_len": data.trim_len(),
+      "loop_len": data.loop_len(),
+      "stretch": data.stretch_type()?.to_string(),
+      "loop": data.loop_type()?.to_string(),
+      "gain": data.gain(),
+      "quantize": data.trig_quant_type()?.to_string(),
+      "trim_start": data.trim_start(),
+      "trim_end": data.trim_end(),
+      "loop_point": data.loop_point(),
+      "slices": slices,
+      "checksum": data.checksum(),
+      "info": fields,
+    }));
+  } else {
+    println!("{}", file.display());
+    print!("{}", inspector);
+  }
+  Ok(())
+}
+
+fn diff(a: &Path, b: &Path, sample_rate: Option<u32>, json: bool) -> io::Result<()> {
+  let (_, ia) = inspect(a, sample_rate)?;
+  let (_, ib) = inspect(b, sample_rate)?;
+  let diff = ia.diff(&ib);
+  if json {
+    let out: Vec<_> = diff.iter()
+      .map(|d| json!({"field": d.field, "a": d.a, "b": d.b}))
+      .collect();
+    println!("{}", serde_json::Value::Array(out));
+  } else {
+    println!("--- {}\n+++ {}", a.display(), b.display());
+    for d in diff {
+      println!("{}", d);
+    }
+  }
+  Ok(())
+}
+
+fn chain(cli: &Cli) -> io::Result<()> {
+  let Command::Chain { output, inputs, ot, trim, fade, pad, slot, walk } = &cli.cmd else { unreachable!() };
+  let mut chain = ChainWriter::new(output)
+    .tempo(ot.tempo.unwrap_or(120.))
+    .ot_writer(ot.apply(OTWriter::new("")));
+  if let Some(db) = trim {
+    chain = chain.trim(*db);
+  }
+  if let Some(ms) = fade {
+    chain = chain.fade(*ms);
+  }
+  if let Some(len) = pad {
+    chain = chain.pad(*len);
+  }
+  if let Some(len) = slot {
+    chain = chain.slot(*len);
+  }
+  for input in walk.collect(inputs, &["wav"])? {
+    if !input.file_name().unwrap().to_string_lossy().starts_with("._") {
+      chain.add_files(input)?;
+    }
+  }
+  let reports: Vec<Report> = chain.input_files().iter()
+    .map(|f| Report::new(f, "added", ""))
+    .collect();
+  print_reports(&reports, cli.json);
+  if !cli.dry_run {
+    chain.process()?;
+  }
+  Ok(())
+}
+
+fn slice(file: &Path, ot: &OtArgs, count: Option<u32>, length: Option<Length>, dry_run: bool) -> io::Result<Report> {
+  if let Some(n) = count.filter(|n| !(1..=64).contains(n)) {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid slice count: {}. valid = [1..64]", n)))
+  }
+  let (len, sample_rate) = audio_info(file)?;
+  let tempo = ot.tempo.unwrap_or(120.);
+  let step = match (count, length) {
+    (_, Some(length)) => length.to_frames(sample_rate, tempo)?,
+    (Some(n), None) => len / n,
+    (None, None) => len,
+  }.max(1);
+  let mut writer = ot.apply(OTWriter::new(file.with_extension("ot")).tempo(tempo))
+    .sample_rate(sample_rate);
+  let mut start = 0;
+  let mut n = 0;
+  while start < len && n < count.unwrap_or(64) {
+    // the last slice of an evenly divided file takes the remainder
+    let end = if count == Some(n + 1) { len } else { (start + step).min(len) };
+    writer.add_slice(start, end, None);
+    start = end;
+    n += 1;
+  }
+  if !dry_run {
+    writer.write(len)?;
+  }
+  Ok(Report::new(file, if dry_run { "dry-run" } else { "generated" }, format!("{} slices", n)))
+}
+
+/// Return a list of problems found in an .ot FILE.
+fn check(file: &Path) -> io::Result<Vec<String>> {
+  let data = OTData::decode(&fs::read(file)?)?;
+  let mut errors = vec![];
+  if data.checksum() != data.calc_checksum() {
+    errors.push(format!("checksum mismatch: {} != {}", data.checksum(), data.calc_checksum()));
+  }
+  if let Err(e) = data.stretch_type() {
+    errors.push(format!("stretch: {}", e));
+  }
+  if let Err(e) = data.loop_type() {
+    errors.push(format!("loop: {}", e));
+  }
+  if let Err(e) = data.trig_quant_type() {
+    errors.push(format!("quantize: {}", e));
+  }
+  if data.slice_count() > 64 {
+    errors.push(format!("too many slices: {}", data.slice_count()));
+  }
+  if data.trim_start() > data.trim_end() {
+    errors.push(format!("trim start {} is after trim end {}", data.trim_start(), data.trim_end()));
+  }
+  for (i, s) in data.slices().iter().enumerate() {
+    if s.start() > s.end() || s.end() > data.trim_end() {
+      errors.push(format!("slice {} out of bounds: {}", i + 1, s));
+    }
+  }
+  match audio_file(file) {
+    Some(audio) => {
+      let (len, _) = audio_info(&audio)?;
+      if data.trim_end() > len {
+	errors.push(format!("trim end {} is past the end of {} ({})", data.trim_end(), audio.display(), len));
+      }
+    },
+    None => errors.push(format!("missing audio file: {}", file.with_extension("wav").display())),
+  }
+  Ok(errors)
+}
+
+fn edit(file: &Path, ot: &OtArgs, dry_run: bool) -> io::Result<Report> {
+  let mut data = OTData::decode(&fs::read(file)?)?;
+  let mut changes = vec![];
+  if let Some(t) = ot.tempo {
+    data.set_tempo(t);
+    changes.push(format!("tempo={}", t));
+  }
+  if let Some(s) = ot.stretch {
+    data.set_stretch_type(s);
+    changes.push(format!("stretch={}", s));
+  }
+  if let Some(l) = ot.loop_type {
+    data.set_loop_type(l);
+    changes.push(format!("loop={}", l));
+  }
+  if let Some(q) = ot.quantize {
+    data.set_trig_quant_type(q);
+    changes.push(format!("quantize={}", q));
+  }
+  if let Some(g) = ot.gain {
+    data.set_gain(g)?;
+    changes.push(format!("gain={}", g));
+  }
+  data.update_checksum();
+  if !dry_run {
+    OTWriter::new(file).data(data).overwrite()?;
+  }
+  Ok(Report::new(file, if dry_run { "dry-run" } else { "edited" }, changes.join(", ")))
+}
+
+fn main() -> io::Result<()> {
+  let cli = Cli::parse();
+  match &cli.cmd {
+    Command::Gen { paths, ot, walk } => {
+      let reports = walk.run(paths, &AUDIO_EXTENSIONS, |f| gen_file(f, ot, cli.dry_run))?;
+      print_run(&cli, &reports)?;
+      check_failed(&reports)
+    },
+    Command::Info { files, sample_rate } => {
+      for f in files {
+	info(f, *sample_rate, cli.json)?;
+      }
+      Ok(())
+    },
+    Command::Diff { a, b, sample_rate } => diff(a, b, *sample_rate, cli.json),
+    Command::Chain { .. } => chain(&cli),
+    Command::Slice { file, ot, count, length } => {
+      let report = slice(file, ot, *count, *length, cli.dry_run)?;
+      print_reports(&[report], cli.json);
+      Ok(())
+    },
+    Command::Check { paths, walk } => {
+      let reports = walk.run(paths, &["ot"], |f| match check(f) {
+	Ok(errors) if errors.is_empty() => Report::new(f, "ok", ""),
+	Ok(errors) => Report::new(f, "failed", errors.join("; ")),
+	Err(e) => Report::new(f, "failed", e.to_string()),
+      })?;
+      print_run(&cli, &reports)?;
+      check_failed(&reports)
+    },
+    Command::Edit { files, ot } => {
+      let reports: Vec<Report> = files.iter()
+	.map(|f| edit(f, ot, cli.dry_run).unwrap_or_else(|e| Report::new(f, "failed", e.to_string())))
+	.collect();
+      print_reports(&reports, cli.json);
+      check_failed(&reports)
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn options_and_aiff() {
+    let args = OtArgs { stretch: Some(StretchType::Beat), loop_type: None, quantize: Some(TrigQuantType::S4), gain: Some(-6), tempo: Some(90.) };
+    let writer = args.apply(OTWriter::new("a.ot").loop_type(LoopType::PingPong));
+    let expected = OTWriter::new("a.ot").loop_type(LoopType::PingPong)
+      .stretch_type(StretchType::Beat)
+      .trig_quant_type(TrigQuantType::S4)
+      .gain_db(-3.)
+      .tempo(90.);
+    assert_eq!(writer, expected);
+
+    // an AIFF file of 1000 frames at 44100 Hz, with a chunk before COMM
+    let tmp = std::env::temp_dir().join("mot_options_and_aiff");
+    let _ = fs::remove_dir_all(&tmp);
+    fs::create_dir_all(&tmp).unwrap();
+    let mut comm = vec![0, 1];
+    comm.extend(1000u32.to_be_bytes());
+    comm.extend(16u16.to_be_bytes());
+    comm.extend([0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]);
+    let mut body = b"AIFF".to_vec();
+    for (id, chunk) in [(b"NAME", &b"odd"[..]), (b"COMM", &comm[..]), (b"SSND", &[0u8; 8 + 2000][..])] {
+      body.extend(id);
+      body.extend((chunk.len() as u32).to_be_bytes());
+      body.extend(chunk);
+      if chunk.len() % 2 == 1 {
+	body.push(0);
+      }
+    }
+    let mut aiff = b"FORM".to_vec();
+    aiff.extend((body.len() as u32).to_be_bytes());
+    aiff.extend(body);
+    fs::write(tmp.join("120.00_a.aif"), aiff).unwrap();
+    assert_eq!(audio_info(&tmp.join("120.00_a.aif")).unwrap(), (1000, 44100));
+    let none = OtArgs { stretch: None, loop_type: None, quantize: None, gain: Some(-48), tempo: None };
+    assert_eq!(gen_file(&tmp.join("120.00_a.aif"), &none, false).status, "generated");
+    let data = OTData::decode(&fs::read(tmp.join("120.00_a.ot")).unwrap()).unwrap();
+    assert_eq!((data.trim_end(), data.gain()), (1000, -48));
+    assert!(check(&tmp.join("120.00_a.ot")).unwrap().is_empty());
+    // slice counts past the 64 slices of an .ot file are rejected
+    assert!(Cli::try_parse_from(["mot", "slice", "a.wav", "--count", "65"]).is_err());
+    assert!(slice(&tmp.join("120.00_a.aif"), &none, Some(65), None, true).is_err());
+    assert_eq!(slice(&tmp.join("120.00_a.aif"), &none, Some(64), None, true).unwrap().message, "64 slices");
+    fs::write(tmp.join("b.aif"), b"FORM\0\0\0\x04WAVE").unwrap();
+    assert!(audio_info(&tmp.join("b.aif")).is_err());
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+  #[test]
+  fn walk_and_check() {
+    let tmp = std::env::temp_dir().join("mot_walk_and_check");
+    let _ = fs::remove_dir_all(&tmp);
+    fs::create_dir_all(tmp.join("sub/deep")).unwrap();
+    let spec = hound::WavSpec { channels: 1, sample_rate: 44100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    for f in ["120.00_a.wav", "sub/120.00_b.WAV", "sub/deep/120.00_c.wav", "sub/skip.wav"] {
+      let mut w = hound::WavWriter::create(tmp.join(f), spec).unwrap();
+      for _ in 0..1000 {
+	w.write_sample(0i16).unwrap();
+      }
+      w.finalize().unwrap();
+    }
+    fs::write(tmp.join("notes.txt"), b"").unwrap();
+    let walk = |depth, include: &[&str], exclude: &[&str]| WalkArgs {
+      depth,
+      include: include.iter().map(|p| glob::Pattern::new(p).unwrap()).collect(),
+      exclude: exclude.iter().map(|p| glob::Pattern::new(p).unwrap()).collect(),
+      jobs: Some(2),
+    };
+    let names = |walk: WalkArgs| {
+      let files = walk.collect(std::slice::from_ref(&tmp), &AUDIO_EXTENSIONS).unwrap();
+      files.iter().map(|f| f.strip_prefix(&tmp).unwrap().to_string_lossy().to_string()).collect::<Vec<_>>()
+    };
+    assert_eq!(names(walk(None, &[], &[])), ["120.00_a.wav", "sub/120.00_b.WAV", "sub/deep/120.00_c.wav", "sub/skip.wav"]);
+    assert_eq!(names(walk(Some(1), &[], &["skip*"])), ["120.00_a.wav", "sub/120.00_b.WAV"]);
+    assert_eq!(names(walk(None, &["120*"], &["*/deep/*"])), ["120.00_a.wav", "sub/120.00_b.WAV"]);
+
+    // run visits the same files from worker threads
+    let none = OtArgs { stretch: None, loop_type: None, quantize: None, gain: None, tempo: None };
+    let reports = walk(None, &["120*"], &[]).run(std::slice::from_ref(&tmp), &AUDIO_EXTENSIONS, |f| gen_file(f, &none, false)).unwrap();
+    assert_eq!(reports.iter().filter(|r| r.status == "generated").count(), 3);
+
+    assert!(check(&tmp.join("120.00_a.ot")).unwrap().is_empty());
+    // audio files matched regardless of case
+    assert!(check(&tmp.join("sub/120.00_b.ot")).unwrap().is_empty());
+    fs::rename(tmp.join("sub/deep/120.00_c.wav"), tmp.join("sub/deep/120.00_c.bak")).unwrap();
+    assert!(check(&tmp.join("sub/deep/120.00_c.ot")).unwrap()[0].starts_with("missing audio file"));
+    let mut bytes = fs::read(tmp.join("120.00_a.ot")).unwrap();
+    bytes[30] ^= 1;
+    fs::write(tmp.join("120.00_a.ot"), bytes).unwrap();
+    assert!(check(&tmp.join("120.00_a.ot")).unwrap()[0].starts_with("checksum mismatch"));
+    fs::write(tmp.join("bad.ot"), b"FORM").unwrap();
+    assert!(check(&tmp.join("bad.ot")).is_err());
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+}
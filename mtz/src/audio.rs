@@ -0,0 +1,458 @@
+//! audio --- WAV and AIFF files stored as FLAC
+//!
+//! The PCM data of an audio file is encoded with `flac`, and everything
+//! else, from the RIFF or FORM header and chunks like cue points and
+//! loops to padding after the samples, is kept aside verbatim, so the
+//! original file is rebuilt byte for byte. Encoded entries are marked
+//! with a PAX header recording the codec and the original size.
+//!
+//! Files are encoded in memory, so only those up to `MAX_SIZE` are
+//! stored as FLAC. Decoding streams the samples out as they are read.
+use crate::meta::Records;
+use crate::{flac, Result};
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::fs;
+
+/// PAX header key naming the codec of an entry.
+pub const CODEC_KEY: &str = "MTZ.codec";
+/// PAX header key holding the size of an entry before encoding.
+pub const SIZE_KEY: &str = "MTZ.size";
+/// Codec of WAV and AIFF entries stored as FLAC.
+pub const CODEC: &str = "flac";
+/// Extensions of files which are tried as audio.
+pub const EXTENSIONS: &[&str] = &["wav", "wave", "aif", "aiff", "aifc"];
+/// Largest file stored as FLAC.
+pub const MAX_SIZE: u64 = 256 << 20;
+const MAGIC: &[u8; 4] = b"MTZA";
+const VERSION: u8 = 1;
+
+/// Layout of the PCM samples of a file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Format {
+  channels: u16,
+  /// Bytes per sample.
+  width: u8,
+  big_endian: bool,
+  /// 8 bit WAV samples are offset by 128.
+  unsigned: bool,
+  sample_rate: u32,
+}
+
+impl Format {
+  fn block_align(&self) -> usize {
+    self.channels as usize * self.width as usize
+  }
+
+  fn is_supported(&self) -> bool {
+    (1..=8).contains(&self.channels) && (1..=3).contains(&self.width)
+  }
+
+  fn sample(&self, b: &[u8]) -> i32 {
+    let mut v = 0i32;
+    for i in 0..b.len() {
+      let byte = if self.big_endian { b[i] } else { b[b.len() - 1 - i] };
+      v = (v << 8) | byte as i32;
+    }
+    let shift = 32 - 8 * b.len() as u32;
+    if self.unsigned {
+      v - 128
+    } else {
+      (v << shift) >> shift
+    }
+  }
+
+  fn write_sample(&self, s: i32, out: &mut Vec<u8>) {
+    let v = if self.unsigned { s + 128 } else { s };
+    let w = self.width as usize;
+    if self.big_endian {
+      out.extend((0..w).rev().map(|i| (v >> (8 * i)) as u8));
+    } else {
+      out.extend((0..w).map(|i| (v >> (8 * i)) as u8));
+    }
+  }
+}
+
+/// Whether PATH has an audio file extension.
+pub fn is_audio(path: &Path) -> bool {
+  path.extension()
+    .and_then(|e| e.to_str())
+    .map(|e| EXTENSIONS.iter().any(|a| e.eq_ignore_ascii_case(a)))
+    .unwrap_or(false)
+}
+
+/// Chunks of a RIFF or IFF file after the 12 byte header, as ID and
+/// the range of their data. Stops at the first truncated chunk.
+fn chunks(b: &[u8], big_endian: bool) -> Vec<([u8; 4], Range<usize>)> {
+  let mut chunks = vec![];
+  let mut pos = 12;
+  while pos + 8 <= b.len() {
+    let size: [u8; 4] = b[pos + 4..pos + 8].try_into().unwrap();
+    let size = if big_endian { u32::from_be_bytes(size) } else { u32::from_le_bytes(size) } as usize;
+    let start = pos + 8;
+    let end = start.saturating_add(size).min(b.len());
+    chunks.push((b[pos..pos + 4].try_into().unwrap(), start..end));
+    // chunks are padded to an even size
+    pos = end.saturating_add(size & 1);
+  }
+  chunks
+}
+
+fn parse_wav(b: &[u8]) -> Option<(Format, Range<usize>)> {
+  if b.len() < 12 || &b[0..4] != b"RIFF" || &b[8..12] != b"WAVE" {
+    return None
+  }
+  let chunks = chunks(b, false);
+  let fmt = &b[chunks.iter().find(|c| &c.0 == b"fmt ")?.1.clone()];
+  let data = chunks.iter().find(|c| &c.0 == b"data")?.1.clone();
+  if fmt.len() < 16 {
+    return None
+  }
+  let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+  // PCM, or extensible with a PCM subformat
+  let pcm = match u16_at(0) {
+    1 => true,
+    0xFFFE => fmt.len() >= 26 && u16_at(24) == 1,
+    _ => false,
+  };
+  let channels = u16_at(2);
+  let bits = u16_at(14);
+  let width = bits.div_ceil(8) as u8;
+  let format = Format {
+    channels,
+    width,
+    big_endian: false,
+    unsigned: width == 1,
+    sample_rate: u32::from_le_bytes(fmt[4..8].try_into().unwrap()),
+  };
+  (pcm && u16_at(12) as usize == format.block_align()).then_some((format, data))
+}
+
+/// The integer part of an 80 bit IEEE 754 extended float.
+fn extended(b: &[u8]) -> u32 {
+  let exp = (u16::from_be_bytes([b[0], b[1]]) & 0x7FFF) as i32 - 16383;
+  let mantissa = u64::from_be_bytes(b[2..10].try_into().unwrap());
+  if !(0..32).contains(&exp) {
+    return 0
+  }
+  (mantissa >> (63 - exp)) as u32
+}
+
+fn parse_aiff(b: &[u8]) -> Option<(Format, Range<usize>)> {
+  if b.len() < 12 || &b[0..4] != b"FORM" || !(&b[8..12] == b"AIFF" || &b[8..12] == b"AIFC") {
+    return None
+  }
+  let chunks = chunks(b, true);
+  let comm = &b[chunks.iter().find(|c| &c.0 == b"COMM")?.1.clone()];
+  let ssnd = chunks.iter().find(|c| &c.0 == b"SSND")?.1.clone();
+  if comm.len() < 18 || ssnd.len() < 8 {
+    return None
+  }
+  let compression = if &b[8..12] == b"AIFC" { comm.get(18..22)? } else { b"NONE" };
+  let big_endian = match compression {
+    b"NONE" | b"twos" => true,
+    b"sowt" => false,
+    _ => return None,
+  };
+  let bits = u16::from_be_bytes([comm[6], comm[7]]);
+  let format = Format {
+    channels: u16::from_be_bytes([comm[0], comm[1]]),
+    width: bits.div_ceil(8) as u8,
+    big_endian,
+    unsigned: false,
+    sample_rate: extended(&comm[8..18]),
+  };
+  // the samples follow the offset and block size fields, and OFFSET
+  // bytes of padding
+  let offset = u32::from_be_bytes(b[ssnd.start..ssnd.start + 4].try_into().unwrap()) as usize;
+  let start = (ssnd.start + 8).saturating_add(offset).min(ssnd.end);
+  Some((format, start..ssnd.end))
+}
+
+/// Encode the WAV or AIFF file BYTES. Returns None if it isn't one with
+/// PCM samples which FLAC can store, or if encoding doesn't make it
+/// smaller.
+pub fn encode(bytes: &[u8]) -> Option<Vec<u8>> {
+  let (format, data) = parse_wav(bytes).or_else(|| parse_aiff(bytes))?;
+  if !format.is_supported() {
+    return None
+  }
+  // a partial sample frame at the end is kept aside
+  let pcm = &bytes[data.start..data.start + (data.len() / format.block_align()) * format.block_align()];
+  if pcm.is_empty() {
+    return None
+  }
+  let samples: Vec<i32> = pcm.chunks(format.width as usize).map(|b| format.sample(b)).collect();
+  let flac = flac::encode(&samples, format.channels as usize, 8 * format.width as u32, format.sample_rate);
+  let (prefix, suffix) = (&bytes[..data.start], &bytes[data.start + pcm.len()..]);
+
+  let mut out = MAGIC.to_vec();
+  out.push(VERSION);
+  out.extend(format.channels.to_le_bytes());
+  out.push(format.width);
+  out.push(format.big_endian as u8 | (format.unsigned as u8) << 1);
+  out.extend(format.sample_rate.to_le_bytes());
+  for part in [prefix, suffix] {
+    out.extend((part.len() as u64).to_le_bytes());
+    out.extend(part);
+  }
+  out.extend(flac);
+  (out.len() < bytes.len()).then_some(out)
+}
+
+fn invalid(msg: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("invalid audio entry: {}", msg))
+}
+
+/// Rebuild the original file from R, written by `encode`, into W.
+/// Returns the size of the file.
+pub fn decode<R: Read, W: Write>(mut r: R, w: &mut W) -> Result<u64> {
+  let mut head = [0u8; 13];
+  r.read_exact(&mut head).map_err(|_| invalid("truncated"))?;
+  if &head[0..4] != MAGIC {
+    return Err(invalid("bad magic").into())
+  }
+  if head[4] != VERSION {
+    return Err(invalid(&format!("unknown version {}", head[4])).into())
+  }
+  let format = Format {
+    channels: u16::from_le_bytes([head[5], head[6]]),
+    width: head[7],
+    big_endian: head[8] & 1 != 0,
+    unsigned: head[8] & 2 != 0,
+    sample_rate: u32::from_le_bytes(head[9..13].try_into().unwrap()),
+  };
+  if !format.is_supported() {
+    return Err(invalid("unsupported sample format").into())
+  }
+  // parts are only as large as the data holding them
+  let mut part = || -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 8];
+    r.read_exact(&mut len).map_err(|_| invalid("truncated"))?;
+    let len = u64::from_le_bytes(len);
+    let mut part = vec![];
+    (&mut r).take(len).read_to_end(&mut part)?;
+    match part.len() as u64 == len {
+      true => Ok(part),
+      false => Err(invalid("truncated")),
+    }
+  };
+  let (prefix, suffix) = (part()?, part()?);
+
+  let mut reader = claxon::FlacReader::new(r).map_err(|e| invalid(&e.to_string()))?;
+  let info = reader.streaminfo();
+  if info.channels != format.channels as u32 || info.bits_per_sample != 8 * format.width as u32 {
+    return Err(invalid("sample format mismatch").into())
+  }
+  let mut out = BufWriter::new(w);
+  out.write_all(&prefix)?;
+  let mut size = prefix.len() as u64;
+  let mut buf = Vec::with_capacity(4);
+  for s in reader.samples() {
+    buf.clear();
+    format.write_sample(s.map_err(|e| invalid(&e.to_string()))?, &mut buf);
+    out.write_all(&buf)?;
+    size += buf.len() as u64;
+  }
+  out.write_all(&suffix)?;
+  out.flush()?;
+  Ok(size + suffix.len() as u64)
+}
+
+/// Whether ENTRY was stored by `encode`, and its original size.
+pub(crate) fn encoded_size<R: Read>(entry: &mut tar::Entry<R>) -> Result<Option<u64>> {
+  let (mut codec, mut size) = (None, None);
+  if let Some(pax) = entry.pax_extensions()? {
+    // as far as tar-rs can read them, see `meta::read`
+    for ext in pax.map_while(|ext| ext.ok()) {
+      match ext.key() {
+	Ok(CODEC_KEY) => codec = ext.value().ok().map(|v| v.to_string()),
+	Ok(SIZE_KEY) => size = ext.value().ok().and_then(|v| v.parse().ok()),
+	_ => (),
+      }
+    }
+  }
+  match codec.as_deref() {
+    None => Ok(None),
+    Some(CODEC) => Ok(Some(size.unwrap_or(0))),
+    Some(c) => Err(invalid(&format!("unknown codec {}", c)).into()),
+  }
+}
+
+/// PAX records marking an entry as encoded from SIZE bytes.
+pub(crate) fn pax_records(size: u64) -> Records {
+  vec![(CODEC_KEY.to_string(), CODEC.into()), (SIZE_KEY.to_string(), size.to_string().into_bytes())]
+}
+
+/// Decode ENTRY into the new file PATH. Its metadata is left to the
+/// caller.
+pub(crate) fn unpack<R: Read>(entry: &mut tar::Entry<R>, path: &Path) -> Result<()> {
+  decode(entry, &mut fs::File::create(path)?)?;
+  Ok(())
+}
+
+/// Decode ENTRY, stored from SIZE bytes, in memory. Entries which
+/// decode to more than SIZE or `MAX_SIZE` are an error.
+pub(crate) fn decode_entry<R: Read>(entry: &mut tar::Entry<R>, size: u64) -> Result<Vec<u8>> {
+  if size > MAX_SIZE {
+    return Err(invalid("too large to decode in memory").into())
+  }
+  let mut out = Bounded { data: vec![], limit: size as usize };
+  decode(entry, &mut out)?;
+  Ok(out.data)
+}
+
+/// A buffer which refuses to grow past LIMIT.
+struct Bounded {
+  data: Vec<u8>,
+  limit: usize,
+}
+
+impl Write for Bounded {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if self.data.len() + buf.len() > self.limit {
+      return Err(invalid("larger than recorded"))
+    }
+    self.data.extend(buf);
+    Ok(buf.len())
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn wav(channels: u16, bits: u16, data: &[u8], extra: &[u8]) -> Vec<u8> {
+    let mut fmt = vec![];
+    fmt.extend(1u16.to_le_bytes());
+    fmt.extend(channels.to_le_bytes());
+    fmt.extend(48000u32.to_le_bytes());
+    fmt.extend((48000 * channels as u32 * bits as u32 / 8).to_le_bytes());
+    fmt.extend((channels * bits / 8).to_le_bytes());
+    fmt.extend(bits.to_le_bytes());
+    let mut body = b"WAVE".to_vec();
+    for (id, chunk) in [(b"fmt ", &fmt[..]), (b"data", data)] {
+      body.extend(id);
+      body.extend((chunk.len() as u32).to_le_bytes());
+      body.extend(chunk);
+      if chunk.len() % 2 == 1 {
+	body.push(0);
+      }
+    }
+    body.extend(extra);
+    let mut out = b"RIFF".to_vec();
+    out.extend((body.len() as u32).to_le_bytes());
+    out.extend(body);
+    out
+  }
+
+  fn decoded(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    decode(data, &mut out)?;
+    Ok(out)
+  }
+
+  fn tone(n: usize) -> Vec<i32> {
+    (0..n).map(|i| ((i as f64 / 30.0).sin() * 12000.0) as i32).collect()
+  }
+
+  #[test]
+  fn wav_round_trip() {
+    let pcm: Vec<u8> = tone(20000).iter().flat_map(|s| (*s as i16).to_le_bytes()).collect();
+    // a trailing odd byte and a cue chunk after the samples
+    let mut data = pcm.clone();
+    data.push(7);
+    let file = wav(2, 16, &data, b"cue \x04\x00\x00\x00abcd");
+    let enc = encode(&file).unwrap();
+    assert!(enc.len() < file.len() / 3);
+    assert_eq!(decoded(&enc).unwrap(), file);
+
+    // crafted lengths and sample counts, and more data than recorded
+    let mut bad = enc.clone();
+    bad[13..21].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(decoded(&bad).is_err());
+    let mut bad = enc.clone();
+    let at = bad.windows(4).position(|w| w == b"fLaC").unwrap() + 8 + 13;
+    bad[at] |= 0x0F;
+    bad[at + 1..at + 5].copy_from_slice(&[0xFF; 4]);
+    assert_eq!(decoded(&bad).unwrap(), file);
+    let mut out = Bounded { data: vec![], limit: file.len() - 1 };
+    assert!(decode(&enc[..], &mut out).is_err());
+
+    let pcm8: Vec<u8> = tone(5000).iter().map(|s| ((s >> 8) + 128) as u8).collect();
+    let file = wav(1, 8, &pcm8, b"");
+    assert_eq!(decoded(&encode(&file).unwrap()).unwrap(), file);
+    let pcm24: Vec<u8> = tone(6000).iter().flat_map(|s| (s << 8).to_le_bytes()[..3].to_vec()).collect();
+    let file = wav(1, 24, &pcm24, b"LIST\x02\x00\x00\x00hi");
+    assert_eq!(decoded(&encode(&file).unwrap()).unwrap(), file);
+
+    // not audio, or not PCM
+    assert!(encode(b"RIFF\x04\x00\x00\x00AVI ").is_none());
+    let mut float = wav(1, 16, &pcm, b"");
+    float[20] = 3;
+    assert!(encode(&float).is_none());
+    assert!(decoded(b"MTZA\x01").is_err());
+
+  }
+
+  #[test]
+  fn aiff_round_trip() {
+    let pcm: Vec<u8> = tone(10000).iter().flat_map(|s| (*s as i16).to_be_bytes()).collect();
+    let mut comm = vec![];
+    comm.extend(1u16.to_be_bytes());
+    comm.extend(10000u32.to_be_bytes());
+    comm.extend(16u16.to_be_bytes());
+    // 44100 as an 80 bit float
+    comm.extend([0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]);
+    let mut ssnd = vec![0, 0, 0, 0, 0, 0, 0, 0];
+    ssnd.extend(&pcm);
+    let mut body = b"AIFF".to_vec();
+    for (id, chunk) in [(b"COMM", &comm), (b"SSND", &ssnd)] {
+      body.extend(id);
+      body.extend((chunk.len() as u32).to_be_bytes());
+      body.extend(chunk);
+    }
+    let mut file = b"FORM".to_vec();
+    file.extend((body.len() as u32).to_be_bytes());
+    file.extend(body);
+    assert_eq!(extended(&comm[8..18]), 44100);
+    assert_eq!(decoded(&encode(&file).unwrap()).unwrap(), file);
+    assert!(is_audio(Path::new("kick.AIF")));
+    assert!(!is_audio(Path::new("kick.mp3")));
+  }
+  #[test]
+  fn pack_audio() {
+    use crate::{extract, list, pack, unpack, verify, Options, UnpackOptions};
+    use std::fs;
+    // WAV files stored as FLAC and restored exactly
+    let tmp = std::env::temp_dir().join("mtz_pack_audio");
+    let _ = fs::remove_dir_all(&tmp);
+    let music = tmp.join("music");
+    fs::create_dir_all(&music).unwrap();
+    let pcm: Vec<u8> = tone(100000).iter().flat_map(|s| (*s as i16).to_le_bytes()).collect();
+    fs::write(music.join("tone.wav"), wav(2, 16, &pcm, b"")).unwrap();
+    fs::write(music.join("fake.wav"), b"not audio").unwrap();
+    let uo = UnpackOptions::new();
+    let plain = tmp.join("plain.tar.zst");
+    let flac = tmp.join("flac.tar.zst");
+    pack(&music, &plain, &Options::new()).unwrap();
+    pack(&music, &flac, &Options::new().audio(true).seekable(true)).unwrap();
+    assert!(fs::metadata(&flac).unwrap().len() < fs::metadata(&plain).unwrap().len() / 2);
+    assert!(crate::info(&flac, &uo).unwrap().settings.unwrap().audio);
+    let mut sizes = vec![];
+    list(&flac, &uo, |e| sizes.push(e.size)).unwrap();
+    assert!(sizes.contains(&fs::metadata(music.join("tone.wav")).unwrap().len()));
+    let v = verify(&flac, &uo).unwrap();
+    assert!(v.manifest && v.problems.is_empty());
+    unpack(&flac, &tmp.join("out"), &uo).unwrap();
+    assert_eq!(fs::read(tmp.join("out/music/tone.wav")).unwrap(), fs::read(music.join("tone.wav")).unwrap());
+    assert_eq!(fs::read(tmp.join("out/music/fake.wav")).unwrap(), b"not audio");
+    extract(&flac, "music/tone.wav", tmp.join("out2"), &uo).unwrap();
+    assert_eq!(fs::read(tmp.join("out2/music/tone.wav")).unwrap(), fs::read(music.join("tone.wav")).unwrap());
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+}
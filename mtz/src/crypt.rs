@@ -0,0 +1,521 @@
+//! crypt --- authenticated encryption of archives
+//!
+//! An encrypted file (usually NAME.tar.zst.enc) is a header followed by
+//! the archive in chunks, each encrypted with XChaCha20-Poly1305 in the
+//! STREAM construction, so files of any size are encrypted and
+//! decrypted with a single chunk in memory, and chunks can't be
+//! reordered, dropped or truncated without failing authentication.
+//!
+//! Header (61 bytes, little endian):
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 8    | magic `MTZCRYPT`                              |
+//! | 8      | 1    | version, 1                                    |
+//! | 9      | 1    | key derivation: 1 = Argon2id, 2 = key file    |
+//! | 10     | 4    | Argon2 memory cost in KiB                     |
+//! | 14     | 4    | Argon2 time cost                              |
+//! | 18     | 4    | Argon2 parallelism                            |
+//! | 22     | 16   | salt                                          |
+//! | 38     | 19   | nonce prefix                                  |
+//! | 57     | 4    | plaintext chunk size                          |
+//!
+//! The key is Argon2id of the passphrase with the salt, or the BLAKE3
+//! key derivation of the salt and the key file contents. Chunk N is
+//! encrypted with the nonce prefix, N as a 32 bit big endian integer
+//! and a byte which is 1 for the last chunk and 0 otherwise, and the
+//! header as associated data. Every chunk but the last holds a full
+//! chunk of plaintext. The last holds less, and may be empty.
+use crate::volume::Input;
+use crate::{Error, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::{fmt, fs};
+
+/// Magic bytes at the start of encrypted files.
+pub const MAGIC: &[u8; 8] = b"MTZCRYPT";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 61;
+const TAG_LEN: usize = 16;
+/// Plaintext bytes per chunk.
+pub const CHUNK_SIZE: usize = 64 << 10;
+/// Default Argon2 memory cost in KiB.
+pub const DEFAULT_M_COST: u32 = 64 << 10;
+/// Default Argon2 time cost.
+pub const DEFAULT_T_COST: u32 = 3;
+/// Largest Argon2 memory cost in KiB, time cost and parallelism read
+/// from a header, which is only authenticated after the key is derived.
+const MAX_M_COST: u32 = 4 << 20;
+const MAX_T_COST: u32 = 64;
+const MAX_PARALLELISM: u32 = 16;
+const KEY_FILE_CONTEXT: &str = "mtz 2026-10-19 key file encryption key";
+
+const KDF_ARGON2: u8 = 1;
+const KDF_KEY_FILE: u8 = 2;
+
+/// A passphrase or key file contents to derive encryption keys from.
+/// Derived keys are cached, so opening a file again is cheap.
+#[derive(Clone)]
+pub struct Secret {
+  kind: Kind,
+  m_cost: u32,
+  t_cost: u32,
+  keys: Arc<Mutex<HashMap<Vec<u8>, [u8; 32]>>>,
+}
+
+#[derive(Clone)]
+enum Kind {
+  Passphrase(String),
+  KeyFile(Vec<u8>),
+}
+
+impl Secret {
+  fn new(kind: Kind) -> Self {
+    Secret { kind, m_cost: DEFAULT_M_COST, t_cost: DEFAULT_T_COST, keys: Default::default() }
+  }
+
+  pub fn passphrase(passphrase: &str) -> Self {
+    Secret::new(Kind::Passphrase(passphrase.to_string()))
+  }
+
+  /// Key file contents, which should be at least 32 random bytes, as
+  /// made by `generate_key`.
+  pub fn key(data: Vec<u8>) -> Self {
+    Secret::new(Kind::KeyFile(data))
+  }
+
+  /// The contents of the key file at PATH.
+  pub fn key_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+    let path = path.as_ref();
+    if !path.is_file() {
+      return Err(Error::NotFound(path.to_path_buf()))
+    }
+    let data = fs::read(path)?;
+    if data.is_empty() {
+      return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("empty key file: {}", path.display()))))
+    }
+    Ok(Secret::key(data))
+  }
+
+  /// Set the Argon2 memory (KiB) and time costs of passphrases, used
+  /// when encrypting. Decryption uses the costs in the header, up to
+  /// 4 GiB of memory and 64 passes.
+  pub fn cost(mut self, m_cost: u32, t_cost: u32) -> Self {
+    self.m_cost = m_cost;
+    self.t_cost = t_cost;
+    self
+  }
+
+  /// The key for the key derivation fields KDF of a header.
+  fn derive(&self, kdf: &[u8]) -> Result<[u8; 32]> {
+    if let Some(key) = self.keys.lock().unwrap().get(kdf) {
+      return Ok(*key)
+    }
+    let le = |i: usize| u32::from_le_bytes(kdf[i..i + 4].try_into().unwrap());
+    let salt = &kdf[13..29];
+    let mut key = [0u8; 32];
+    match (kdf[0], &self.kind) {
+      (KDF_ARGON2, Kind::Passphrase(passphrase)) => {
+	if le(1) > MAX_M_COST || le(5) > MAX_T_COST || le(9) > MAX_PARALLELISM {
+	  return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, "key derivation parameters too large")))
+	}
+	let params = argon2::Params::new(le(1), le(5), le(9), Some(32))
+	  .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid key derivation parameters: {}", e)))?;
+	argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+	  .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+	  .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("key derivation failed: {}", e)))?;
+      },
+      (KDF_KEY_FILE, Kind::KeyFile(data)) => {
+	key = *blake3::Hasher::new_derive_key(KEY_FILE_CONTEXT).update(salt).update(data).finalize().as_bytes();
+      },
+      (KDF_ARGON2, _) => return Err(Error::Secret("encrypted with a passphrase, not a key file")),
+      (KDF_KEY_FILE, _) => return Err(Error::Secret("encrypted with a key file, not a passphrase")),
+      (kdf, _) => return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("unknown key derivation {}", kdf)))),
+    }
+    self.keys.lock().unwrap().insert(kdf.to_vec(), key);
+    Ok(key)
+  }
+}
+
+impl fmt::Debug for Secret {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.kind {
+      Kind::Passphrase(_) => write!(f, "Secret::Passphrase {{ m_cost: {}, t_cost: {}, .. }}", self.m_cost, self.t_cost),
+      Kind::KeyFile(_) => write!(f, "Secret::KeyFile(..)"),
+    }
+  }
+}
+
+/// 32 random bytes, for use as a key file.
+pub fn generate_key() -> Result<Vec<u8>> {
+  random(32)
+}
+
+fn random(n: usize) -> Result<Vec<u8>> {
+  let mut buf = vec![0u8; n];
+  getrandom::getrandom(&mut buf).map_err(|e| io::Error::other(e.to_string()))?;
+  Ok(buf)
+}
+
+/// Error of chunks which fail authentication, carried through the
+/// readers above as an `io::Error` and turned into
+/// `Error::Authentication`.
+#[derive(Debug)]
+pub struct AuthError;
+
+impl fmt::Display for AuthError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "authentication failed")
+  }
+}
+
+impl std::error::Error for AuthError {}
+
+fn auth_error() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, AuthError)
+}
+
+/// Whether E was caused by an `AuthError`, through any wrapping.
+pub(crate) fn is_auth_error(e: &io::Error) -> bool {
+  let mut next: Option<&(dyn std::error::Error + 'static)> = e.get_ref().map(|e| e as _);
+  while let Some(err) = next {
+    if err.is::<AuthError>() {
+      return true
+    }
+    next = match err.downcast_ref::<io::Error>() {
+      Some(e) => e.get_ref().map(|e| e as _),
+      None => err.source(),
+    };
+  }
+  false
+}
+
+/// The parsed header and the cipher keyed from it.
+struct Stream {
+  header: Vec<u8>,
+  cipher: XChaCha20Poly1305,
+  chunk_size: usize,
+}
+
+impl Stream {
+  fn new(header: Vec<u8>, secret: &Secret) -> Result<Self> {
+    let key = secret.derive(&header[9..38])?;
+    let chunk_size = u32::from_le_bytes(header[57..61].try_into().unwrap()) as usize;
+    if chunk_size == 0 || chunk_size > 16 << 20 {
+      return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("invalid chunk size {}", chunk_size))))
+    }
+    Ok(Stream { cipher: XChaCha20Poly1305::new(&key.into()), header, chunk_size })
+  }
+
+  fn nonce(&self, index: u64, last: bool) -> io::Result<XNonce> {
+    let index = u32::try_from(index).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many chunks"))?;
+    let mut nonce = [0u8; 24];
+    nonce[..19].copy_from_slice(&self.header[38..57]);
+    nonce[19..23].copy_from_slice(&index.to_be_bytes());
+    nonce[23] = last as u8;
+    Ok(nonce.into())
+  }
+
+  fn seal(&self, index: u64, last: bool, msg: &[u8]) -> io::Result<Vec<u8>> {
+    self.cipher.encrypt(&self.nonce(index, last)?, Payload { msg, aad: &self.header })
+      .map_err(|_| io::Error::other("encryption failed"))
+  }
+
+  fn open(&self, index: u64, last: bool, msg: &[u8]) -> io::Result<Vec<u8>> {
+    self.cipher.decrypt(&self.nonce(index, last)?, Payload { msg, aad: &self.header })
+      .map_err(|_| auth_error())
+  }
+}
+
+/// Encrypts everything written through it into W.
+pub struct Writer<W: Write> {
+  inner: W,
+  stream: Stream,
+  buf: Vec<u8>,
+  index: u64,
+}
+
+impl<W: Write> Writer<W> {
+  /// Start an encrypted file on W, with a key derived from SECRET.
+  pub fn new(mut inner: W, secret: &Secret) -> Result<Self> {
+    let mut header = MAGIC.to_vec();
+    header.push(VERSION);
+    match secret.kind {
+      Kind::Passphrase(_) => {
+	header.push(KDF_ARGON2);
+	for n in [secret.m_cost, secret.t_cost, 1] {
+	  header.extend(n.to_le_bytes());
+	}
+      },
+      Kind::KeyFile(_) => {
+	header.push(KDF_KEY_FILE);
+	header.extend([0u8; 12]);
+      },
+    }
+    header.extend(random(16 + 19)?);
+    header.extend((CHUNK_SIZE as u32).to_le_bytes());
+    let stream = Stream::new(header, secret)?;
+    inner.write_all(&stream.header)?;
+    Ok(Writer { inner, stream, buf: Vec::with_capacity(CHUNK_SIZE), index: 0 })
+  }
+
+  fn write_chunk(&mut self, last: bool) -> io::Result<()> {
+    let len = self.buf.len().min(CHUNK_SIZE);
+    let sealed = self.stream.seal(self.index, last, &self.buf[..len])?;
+    self.inner.write_all(&sealed)?;
+    self.buf.drain(..len);
+    self.index += 1;
+    Ok(())
+  }
+
+  /// Write the last chunk, and return W.
+  pub fn finish(mut self) -> Result<W> {
+    // the last chunk is always short, so a full one is followed by an
+    // empty one
+    if self.buf.len() == CHUNK_SIZE {
+      self.write_chunk(false)?;
+    }
+    self.write_chunk(true)?;
+    self.inner.flush()?;
+    Ok(self.inner)
+  }
+}
+
+impl<W: Write> Write for Writer<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    // a full chunk is only written once more data follows it
+    if self.buf.len() == CHUNK_SIZE {
+      self.write_chunk(false)?;
+    }
+    let n = buf.len().min(CHUNK_SIZE - self.buf.len());
+    self.buf.extend(&buf[..n]);
+    Ok(n)
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Decrypts an encrypted file, checking every chunk as it is read.
+pub struct Reader<R: Read> {
+  inner: R,
+  stream: Stream,
+  chunk: Vec<u8>,
+  index: u64,
+  last: bool,
+  pos: usize,
+}
+
+impl<R: Read> Reader<R> {
+  /// Read the header from R and derive the key from SECRET. The first
+  /// chunk is decrypted right away, so a wrong key fails here.
+  pub fn new(mut inner: R, secret: &Secret) -> Result<Self> {
+    let mut header = vec![0u8; HEADER_LEN];
+    inner.read_exact(&mut header).map_err(|e| match e.kind() {
+      io::ErrorKind::UnexpectedEof => io::Error::new(e.kind(), "truncated encryption header"),
+      _ => e,
+    })?;
+    if &header[..8] != MAGIC {
+      return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, "not an encrypted file")))
+    }
+    if header[8] != VERSION {
+      return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("unknown encryption version {}", header[8]))))
+    }
+    let stream = Stream::new(header, secret)?;
+    let mut r = Reader { inner, stream, chunk: vec![], index: 0, last: false, pos: 0 };
+    r.load()?;
+    Ok(r)
+  }
+
+  /// Read and decrypt chunk INDEX from the current position of the
+  /// inner reader.
+  fn load(&mut self) -> io::Result<()> {
+    let mut sealed = Vec::with_capacity(self.stream.chunk_size + TAG_LEN);
+    (&mut self.inner).take((self.stream.chunk_size + TAG_LEN) as u64).read_to_end(&mut sealed)?;
+    // only the last chunk is short
+    self.last = sealed.len() < self.stream.chunk_size + TAG_LEN;
+    self.chunk = self.stream.open(self.index, self.last, &sealed)?;
+    self.pos = 0;
+    Ok(())
+  }
+}
+
+impl<R: Read> Read for Reader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.pos == self.chunk.len() && !self.last {
+      self.index += 1;
+      self.load()?;
+    }
+    let n = buf.len().min(self.chunk.len() - self.pos);
+    buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+    self.pos += n;
+    Ok(n)
+  }
+}
+
+impl<R: Read + Seek> Seek for Reader<R> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let (size, sealed_size) = (self.stream.chunk_size as u64, (self.stream.chunk_size + TAG_LEN) as u64);
+    let body = self.inner.seek(SeekFrom::End(0))? - HEADER_LEN as u64;
+    let last_index = body / sealed_size;
+    let len = last_index * size + (body % sealed_size).checked_sub(TAG_LEN as u64).ok_or_else(auth_error)?;
+    let current = self.index * size + self.pos as u64;
+    let target = match pos {
+      SeekFrom::Start(n) => n as i64,
+      SeekFrom::End(n) => len as i64 + n,
+      SeekFrom::Current(n) => current as i64 + n,
+    };
+    if target < 0 {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"))
+    }
+    let index = (target as u64 / size).min(last_index);
+    self.inner.seek(SeekFrom::Start(HEADER_LEN as u64 + index * sealed_size))?;
+    self.index = index;
+    self.load()?;
+    self.pos = (target as u64 - index * size).min(self.chunk.len() as u64) as usize;
+    Ok(target as u64)
+  }
+}
+
+/// An input file, decrypted if it is encrypted.
+pub enum Plain {
+  Raw(Input),
+  Decrypted(Box<Reader<Input>>),
+}
+
+/// Open SRC, decrypting it with SECRET if it is encrypted.
+pub fn open(src: &Path, secret: Option<&Secret>) -> Result<Plain> {
+  let mut input = Input::open(src)?;
+  let mut magic = vec![];
+  (&mut input).take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+  input.seek(SeekFrom::Start(0))?;
+  match (magic == MAGIC, secret) {
+    (false, _) => Ok(Plain::Raw(input)),
+    (true, Some(secret)) => Ok(Plain::Decrypted(Box::new(Reader::new(input, secret)?))),
+    (true, None) => Err(Error::Encrypted(src.to_path_buf())),
+  }
+}
+
+impl Read for Plain {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self {
+      Plain::Raw(r) => r.read(buf),
+      Plain::Decrypted(r) => r.read(buf),
+    }
+  }
+}
+
+impl Seek for Plain {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    match self {
+      Plain::Raw(r) => r.seek(pos),
+      Plain::Decrypted(r) => r.seek(pos),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn round_trip() {
+    let secret = Secret::passphrase("correct horse").cost(256, 1);
+    for len in [0, 10, CHUNK_SIZE, 3 * CHUNK_SIZE + 5] {
+      let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+      let mut w = Writer::new(vec![], &secret).unwrap();
+      w.write_all(&data).unwrap();
+      let sealed = w.finish().unwrap();
+      let mut out = vec![];
+      Reader::new(&sealed[..], &secret).unwrap().read_to_end(&mut out).unwrap();
+      assert_eq!(out, data);
+
+      let mut r = Reader::new(io::Cursor::new(&sealed), &secret).unwrap();
+      assert_eq!(r.seek(SeekFrom::End(0)).unwrap(), len as u64);
+      if len > 0 {
+	r.seek(SeekFrom::Start(len as u64 - 1)).unwrap();
+	let mut b = [0u8];
+	r.read_exact(&mut b).unwrap();
+	assert_eq!(b[0], data[len - 1]);
+      }
+    }
+
+    let mut w = Writer::new(vec![], &secret).unwrap();
+    w.write_all(&[7u8; 2 * CHUNK_SIZE]).unwrap();
+    let sealed = w.finish().unwrap();
+    let read = |sealed: &[u8], secret: &Secret| -> io::Result<Vec<u8>> {
+      let mut out = vec![];
+      Reader::new(sealed, secret).map_err(io::Error::other)?.read_to_end(&mut out)?;
+      Ok(out)
+    };
+    assert!(matches!(Reader::new(&sealed[..], &Secret::passphrase("wrong").cost(256, 1)), Err(Error::Authentication)));
+    assert!(matches!(Reader::new(&sealed[..], &Secret::key(vec![1])), Err(Error::Secret(_))));
+    // tampered, truncated at a chunk boundary, and with the last chunk
+    // dropped
+    let mut bad = sealed.clone();
+    bad[HEADER_LEN + CHUNK_SIZE + 100] ^= 1;
+    assert!(is_auth_error(&read(&bad, &secret).unwrap_err()));
+    assert!(is_auth_error(&read(&sealed[..HEADER_LEN + CHUNK_SIZE + TAG_LEN], &secret).unwrap_err()));
+    assert!(is_auth_error(&read(&sealed[..sealed.len() - TAG_LEN], &secret).unwrap_err()));
+
+    // a damaged header is not a wrong key
+    assert!(matches!(Reader::new(&sealed[..HEADER_LEN - 1], &secret), Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+
+    // an inflated memory cost is rejected before any work
+    let mut big = sealed.clone();
+    big[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(matches!(Reader::new(&big[..], &secret), Err(Error::Io(e)) if e.kind() == io::ErrorKind::InvalidData));
+    big[10..14].copy_from_slice(&256u32.to_le_bytes());
+    big[18..22].copy_from_slice(&(MAX_PARALLELISM + 1).to_le_bytes());
+    assert!(matches!(Reader::new(&big[..], &secret), Err(Error::Io(_))));
+
+    let key = Secret::key(generate_key().unwrap());
+    let mut w = Writer::new(vec![], &key).unwrap();
+    w.write_all(b"hello").unwrap();
+    assert_eq!(read(&w.finish().unwrap(), &key).unwrap(), b"hello");
+  }
+  #[test]
+  fn encrypted_archives() {
+    use crate::formats::Format;
+    use crate::{compress, extract, list, pack, unpack, verify, Options, UnpackOptions};
+    // seekable and split, with a passphrase
+    let tmp = std::env::temp_dir().join("mtz_encrypted_archives");
+    let _ = fs::remove_dir_all(&tmp);
+    let src = tmp.join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("a.txt"), b"hello").unwrap();
+    fs::write(src.join("sub/b.bin"), vec![7u8; 100000]).unwrap();
+    let secret = Secret::passphrase("hunter2").cost(256, 1);
+    let sealed = tmp.join("src.tar.zst.enc");
+    pack(&src, &sealed, &Options::new().seekable(true).split(Some(40000)).encrypt(Some(secret.clone()))).unwrap();
+    let info = crate::info(&sealed, &UnpackOptions::new()).unwrap();
+    assert!(info.encrypted && info.format == Format::Encrypted && info.settings.is_none());
+    assert!(matches!(unpack(&sealed, &tmp.join("out"), &UnpackOptions::new()), Err(Error::Encrypted(_))));
+    let wrong = UnpackOptions::new().secret(Some(Secret::passphrase("hunter3")));
+    assert!(matches!(list(&sealed, &wrong, |_| ()), Err(Error::Authentication)));
+    let key = UnpackOptions::new().secret(Some(secret));
+    let info = crate::info(&sealed, &key).unwrap();
+    assert!(info.encrypted && info.format == Format::Zstd && info.frames.is_some());
+    assert!(verify(&sealed, &key).unwrap().problems.is_empty());
+    unpack(&sealed, &tmp.join("out"), &key).unwrap();
+    assert_eq!(fs::read(tmp.join("out/src/sub/b.bin")).unwrap(), vec![7u8; 100000]);
+    extract(&sealed, "src/a.txt", tmp.join("out2"), &key).unwrap();
+    assert_eq!(fs::read(tmp.join("out2/src/a.txt")).unwrap(), fs::read(src.join("a.txt")).unwrap());
+
+    // a key file, and a file compressed with it
+    let secret = Secret::key(generate_key().unwrap());
+    let key = UnpackOptions::new().secret(Some(secret.clone()));
+    compress(&src.join("a.txt"), &tmp.join("a.txt.zst.enc"), &Options::new().encrypt(Some(secret))).unwrap();
+    unpack(&tmp.join("a.txt.zst.enc"), &tmp.join("out3"), &key).unwrap();
+    assert_eq!(fs::read(tmp.join("out3/a.txt")).unwrap(), fs::read(src.join("a.txt")).unwrap());
+    let mut bytes = fs::read(tmp.join("a.txt.zst.enc")).unwrap();
+    let n = bytes.len() - 3;
+    bytes[n] ^= 1;
+    fs::write(tmp.join("b.txt.zst.enc"), bytes).unwrap();
+    assert!(matches!(unpack(&tmp.join("b.txt.zst.enc"), &tmp.join("out3"), &key), Err(Error::Authentication)));
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+}
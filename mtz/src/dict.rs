@@ -0,0 +1,135 @@
+//! dict --- zstd dictionaries for collections of small files
+use crate::{Error, Result};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::fs;
+
+/// Magic number at the start of zstd dictionaries.
+pub const DICT_MAGIC: u32 = 0xEC30A437;
+/// Default maximum size of trained dictionaries.
+pub const DEFAULT_DICT_SIZE: usize = 110 << 10;
+
+/// A zstd dictionary, identified by the ID in its header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dictionary {
+  id: u32,
+  data: Vec<u8>,
+}
+
+impl Dictionary {
+  pub fn new(data: Vec<u8>) -> Result<Self> {
+    let le = |b: &[u8]| u32::from_le_bytes(b.try_into().unwrap());
+    if data.len() < 8 || le(&data[0..4]) != DICT_MAGIC {
+      return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, "not a zstd dictionary")))
+    }
+    Ok(Dictionary { id: le(&data[4..8]), data })
+  }
+
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+    let path = path.as_ref();
+    if !path.is_file() {
+      return Err(Error::NotFound(path.to_path_buf()))
+    }
+    Dictionary::new(fs::read(path)?)
+  }
+
+  pub fn id(&self) -> u32 {
+    self.id
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.data
+  }
+}
+
+/// The dictionary with ID among DICTS, if ID is set. Frames compressed
+/// without a dictionary use none.
+pub fn find(dicts: &[Dictionary], id: Option<u32>) -> Result<&[u8]> {
+  match id {
+    None | Some(0) => Ok(&[]),
+    Some(id) => dicts.iter()
+      .find(|d| d.id == id)
+      .map(|d| d.as_bytes())
+      .ok_or_else(|| Error::Dictionary { needed: id, given: dicts.iter().map(|d| d.id).collect() }),
+  }
+}
+
+fn collect(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+  let meta = fs::symlink_metadata(path)?;
+  if meta.is_dir() {
+    let mut entries = fs::read_dir(path)?
+      .map(|e| e.map(|e| e.path()))
+      .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for e in entries {
+      collect(&e, files)?;
+    }
+  } else if meta.is_file() && meta.len() > 0 {
+    files.push(path.to_path_buf());
+  }
+  Ok(())
+}
+
+/// Train a dictionary of at most MAX_SIZE bytes on every file under
+/// DIR. Returns the dictionary and the number of samples used.
+pub fn train<P: AsRef<Path>>(dir: P, max_size: usize) -> Result<(Dictionary, usize)> {
+  let dir = dir.as_ref();
+  if !dir.exists() {
+    return Err(Error::NotFound(dir.to_path_buf()))
+  }
+  let mut files = vec![];
+  collect(dir, &mut files)?;
+  let mut data = vec![];
+  let mut sizes = vec![];
+  for f in &files {
+    let start = data.len();
+    data.extend(fs::read(f)?);
+    sizes.push(data.len() - start);
+  }
+  let dict = zstd::dict::from_continuous(&data, &sizes, max_size)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("training failed on {} samples: {}", files.len(), e)))?;
+  Ok((Dictionary::new(dict)?, files.len()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn train_and_find() {
+    let tmp = std::env::temp_dir().join("mtz_dict");
+    let _ = fs::remove_dir_all(&tmp);
+    fs::create_dir_all(&tmp).unwrap();
+    for i in 0..200u32 {
+      let sample: Vec<u8> = (0..400u32).map(|j| ((j * j + i) % 97) as u8).collect();
+      fs::write(tmp.join(format!("{}.raw", i)), sample).unwrap();
+    }
+    let (dict, n) = train(&tmp, 4096).unwrap();
+    assert_eq!(n, 200);
+    assert!(dict.as_bytes().len() <= 4096);
+    assert_eq!(find(std::slice::from_ref(&dict), Some(dict.id())).unwrap(), dict.as_bytes());
+    assert!(find(&[], None).unwrap().is_empty());
+    assert!(matches!(find(&[dict], Some(1)), Err(Error::Dictionary { needed: 1, .. })));
+    assert!(Dictionary::new(b"nope".to_vec()).is_err());
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+  #[test]
+  fn compress_with_dict() {
+    use crate::{compress, unpack, Options, UnpackOptions};
+    let tmp = std::env::temp_dir().join("mtz_compress_with_dict");
+    let _ = fs::remove_dir_all(&tmp);
+    let samples = tmp.join("samples");
+    fs::create_dir_all(&samples).unwrap();
+    for i in 0..100u32 {
+      fs::write(samples.join(format!("{}.raw", i)), (0..300u32).map(|j| ((j * j + i) % 89) as u8).collect::<Vec<u8>>()).unwrap();
+    }
+    let (dict, _) = train(&samples, 2048).unwrap();
+    let small = tmp.join("small.raw.zst");
+    compress(&samples.join("5.raw"), &small, &Options::new().dict(Some(dict.clone()))).unwrap();
+    assert_eq!(crate::info(&small, &UnpackOptions::new()).unwrap().header.unwrap().dict_id, Some(dict.id()));
+    let out = tmp.join("out");
+    assert!(matches!(unpack(&small, &out, &UnpackOptions::new()), Err(Error::Dictionary { given, .. }) if given.is_empty()));
+    unpack(&small, &out, &UnpackOptions::new().dict(dict)).unwrap();
+    assert_eq!(fs::read(out.join("small.raw")).unwrap(), fs::read(samples.join("5.raw")).unwrap());
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+}
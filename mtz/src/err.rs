@@ -0,0 +1,90 @@
+//! err --- mtz error type
+use crate::manifest::Problem;
+use crate::volume::VolumeProblem;
+use std::path::PathBuf;
+use std::{fmt, io};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+  Io(io::Error),
+  /// A path which can't be used as an archive source or destination.
+  InvalidPath(PathBuf),
+  NotFound(PathBuf),
+  /// An archive without a seek table, where one is required.
+  NotSeekable(PathBuf),
+  /// Files which don't match the archive manifest.
+  Manifest(Vec<Problem>),
+  /// Volumes of a split archive which are missing or truncated.
+  Volumes(Vec<VolumeProblem>),
+  /// A file compressed with dictionary NEEDED, which isn't among the
+  /// dictionaries GIVEN.
+  Dictionary { needed: u32, given: Vec<u32> },
+  /// An encrypted file, read without a passphrase or key.
+  Encrypted(PathBuf),
+  /// A passphrase or key of the wrong kind for an encrypted file.
+  Secret(&'static str),
+  /// Encrypted data which fails authentication, because the passphrase
+  /// or key is wrong or the data was modified.
+  Authentication,
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Io(e) => write!(f, "{}", e),
+      Error::InvalidPath(p) => write!(f, "invalid path: {}", p.display()),
+      Error::NotFound(p) => write!(f, "file does not exist: {}", p.display()),
+      Error::NotSeekable(p) => write!(f, "archive is not seekable (pack with --seekable): {}", p.display()),
+      Error::Manifest(problems) => {
+	write!(f, "{} files failed the manifest check", problems.len())?;
+	for p in problems {
+	  write!(f, "\n  {}", p)?;
+	}
+	Ok(())
+      },
+      Error::Dictionary { needed, given } if given.is_empty() => {
+	write!(f, "compressed with dictionary {}, which must be given with --dict", needed)
+      },
+      Error::Dictionary { needed, given } => {
+	write!(f, "compressed with dictionary {}, but the given dictionaries are {:?}", needed, given)
+      },
+      Error::Encrypted(p) => write!(f, "file is encrypted (give --passphrase-file or --key-file): {}", p.display()),
+      Error::Secret(msg) => write!(f, "{}", msg),
+      Error::Authentication => write!(f, "authentication failed: wrong passphrase or key, or the file was modified"),
+      Error::Volumes(problems) => {
+	write!(f, "{} volumes are missing or truncated", problems.len())?;
+	for p in problems {
+	  write!(f, "\n  {}", p)?;
+	}
+	Ok(())
+      },
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::Io(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for Error {
+  fn from(e: io::Error) -> Self {
+    if crate::crypt::is_auth_error(&e) {
+      Error::Authentication
+    } else {
+      Error::Io(e)
+    }
+  }
+}
+
+impl From<zip::result::ZipError> for Error {
+  fn from(e: zip::result::ZipError) -> Self {
+    Error::from(io::Error::from(e))
+  }
+}
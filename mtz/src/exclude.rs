@@ -0,0 +1,109 @@
+//! exclude --- skip files when packing, with gitignore-style patterns
+use crate::{Options, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::io;
+use std::path::Path;
+
+/// Name of the file holding exclude patterns for its directory and
+/// everything below it.
+pub const IGNORE_FILE: &str = ".mtzignore";
+
+/// Common junk excluded unless turned off with
+/// `Options::default_excludes(false)`.
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+  ".DS_Store",
+  "._*",
+  ".Spotlight-V100/",
+  ".Trashes/",
+  ".fseventsd/",
+  "Thumbs.db",
+  "desktop.ini",
+  ".cache/",
+  "__pycache__/",
+  "target/",
+];
+
+fn pattern_err(e: ignore::Error) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+/// The exclude patterns in effect while walking a tree. Patterns from
+/// deeper .mtzignore files take precedence, as in git.
+pub struct Excludes {
+  stack: Vec<Gitignore>,
+}
+
+impl Excludes {
+  /// Patterns from OPTS, rooted at ROOT.
+  pub fn new(root: &Path, opts: &Options) -> Result<Self> {
+    let mut builder = GitignoreBuilder::new(root);
+    if opts.default_excludes {
+      for p in DEFAULT_EXCLUDES {
+	builder.add_line(None, p).map_err(pattern_err)?;
+      }
+    }
+    for p in &opts.excludes {
+      builder.add_line(None, p).map_err(pattern_err)?;
+    }
+    Ok(Excludes { stack: vec![builder.build().map_err(pattern_err)?] })
+  }
+
+  /// Add the .mtzignore file in DIR, if there is one. Returns true if
+  /// patterns were added, in which case `leave` should be called after
+  /// walking DIR.
+  pub fn enter(&mut self, dir: &Path) -> Result<bool> {
+    let file = dir.join(IGNORE_FILE);
+    if !file.is_file() {
+      return Ok(false)
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(e) = builder.add(&file) {
+      return Err(pattern_err(e).into())
+    }
+    self.stack.push(builder.build().map_err(pattern_err)?);
+    Ok(true)
+  }
+
+  pub fn leave(&mut self) {
+    self.stack.pop();
+  }
+
+  pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+    for ignore in self.stack.iter().rev() {
+      match ignore.matched(path, is_dir) {
+	Match::Ignore(_) => return true,
+	Match::Whitelist(_) => return false,
+	Match::None => (),
+      }
+    }
+    false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn excludes() {
+    let tmp = std::env::temp_dir().join("mtz_excludes");
+    let _ = std::fs::remove_dir_all(&tmp);
+    std::fs::create_dir_all(tmp.join("sub")).unwrap();
+    std::fs::write(tmp.join("sub").join(IGNORE_FILE), "*.wav\n!keep.wav\n").unwrap();
+
+    let mut ex = Excludes::new(&tmp, &Options::new().exclude("*.tmp")).unwrap();
+    assert!(ex.is_excluded(&tmp.join(".DS_Store"), false));
+    assert!(ex.is_excluded(&tmp.join("sub/target"), true));
+    assert!(!ex.is_excluded(&tmp.join("sub/target"), false));
+    assert!(ex.is_excluded(&tmp.join("a.tmp"), false));
+    assert!(ex.enter(&tmp.join("sub")).unwrap());
+    assert!(ex.is_excluded(&tmp.join("sub/a.wav"), false));
+    assert!(!ex.is_excluded(&tmp.join("sub/keep.wav"), false));
+    ex.leave();
+    assert!(!ex.is_excluded(&tmp.join("sub/a.wav"), false));
+
+    let ex = Excludes::new(&tmp, &Options::new().default_excludes(false)).unwrap();
+    assert!(!ex.is_excluded(&tmp.join(".DS_Store"), false));
+    std::fs::remove_dir_all(&tmp).unwrap();
+  }
+}
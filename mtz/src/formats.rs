@@ -0,0 +1,306 @@
+//! formats --- zstd, gzip, xz, bzip2 and zip inputs
+//!
+//! Inputs are recognized by their first bytes rather than their names,
+//! and tarballs by a valid tar header at the start of the decompressed
+//! stream, so `.tgz`, `.tar.gz` and misnamed files all work. Encrypted
+//! files are decrypted first, given the secret.
+use crate::crypt::{self, Plain};
+use crate::frame::FrameHeader;
+use crate::volume::{self, Input};
+use crate::{dict, frame, Error, Result, Settings, UnpackOptions, SETTINGS_MAGIC};
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Format of an input file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+  Zstd,
+  Gzip,
+  Xz,
+  Bzip2,
+  Zip,
+  /// Encrypted by `crypt`, of unknown format without the secret.
+  Encrypted,
+  /// An uncompressed tarball.
+  Tar,
+  /// Anything else.
+  Raw,
+}
+
+impl Format {
+  /// Detect the format from the first bytes HEAD of a file.
+  pub fn detect(head: &[u8]) -> Format {
+    let le = |b: &[u8]| u32::from_le_bytes(b[0..4].try_into().unwrap());
+    match head {
+      _ if head.starts_with(crypt::MAGIC) => Format::Encrypted,
+      [0x28, 0xB5, 0x2F, 0xFD, ..] => Format::Zstd,
+      // our archives start with a skippable settings frame
+      _ if head.len() >= 4 && le(head) & 0xFFFFFFF0 == frame::SKIPPABLE_MAGIC => Format::Zstd,
+      [0x1F, 0x8B, ..] => Format::Gzip,
+      [0xFD, b'7', b'z', b'X', b'Z', 0, ..] => Format::Xz,
+      [b'B', b'Z', b'h', ..] => Format::Bzip2,
+      [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => Format::Zip,
+      _ if is_tar_header(head) => Format::Tar,
+      _ => Format::Raw,
+    }
+  }
+
+  /// The extension files of this format usually have.
+  pub fn extension(self) -> Option<&'static str> {
+    match self {
+      Format::Zstd => Some("zst"),
+      Format::Gzip => Some("gz"),
+      Format::Xz => Some("xz"),
+      Format::Bzip2 => Some("bz2"),
+      Format::Zip => Some("zip"),
+      Format::Encrypted => Some("enc"),
+      Format::Tar => Some("tar"),
+      Format::Raw => None,
+    }
+  }
+}
+
+/// Whether BLOCK starts with a tar header with a valid checksum.
+pub fn is_tar_header(block: &[u8]) -> bool {
+  if block.len() < 512 {
+    return false
+  }
+  let header = tar::Header::from_byte_slice(&block[..512]);
+  // the checksum is computed with its own field as spaces
+  let sum: u32 = block[..512].iter().enumerate()
+    .map(|(i, b)| if (148..156).contains(&i) { b' ' as u32 } else { *b as u32 })
+    .sum();
+  header.cksum().map(|c| c == sum).unwrap_or(false)
+}
+
+/// Detect the format of SRC, which may be split into volumes. The
+/// format inside encrypted files is detected if OPTS hold a secret.
+pub fn detect<P: AsRef<Path>>(src: P, opts: &UnpackOptions) -> Result<Format> {
+  let src = src.as_ref();
+  let mut head = vec![];
+  Input::open(src)?.take(512).read_to_end(&mut head)?;
+  if Format::detect(&head) == Format::Encrypted && opts.secret.is_some() {
+    head.clear();
+    crypt::open(src, opts.secret.as_ref())?.take(512).read_to_end(&mut head)?;
+  }
+  Ok(Format::detect(&head))
+}
+
+/// An input file, opened according to its format.
+pub enum Source {
+  Zip(zip::ZipArchive<Plain>),
+  /// A tarball, decompressed.
+  Tar(Box<dyn Read>),
+  /// Compressed data of the given format, decompressed.
+  Data(Box<dyn Read>, Format),
+}
+
+/// Read up to N bytes from the start of R, returning them and R with
+/// them put back.
+fn peek(mut r: Box<dyn Read>, n: u64) -> Result<(Vec<u8>, Box<dyn Read>)> {
+  let mut head = vec![];
+  (&mut r).take(n).read_to_end(&mut head)?;
+  Ok((head.clone(), Box::new(io::Cursor::new(head).chain(r))))
+}
+
+/// The dictionary ID of the zstd stream starting with HEAD, from our
+/// settings frame or the first frame header.
+fn dict_id(head: &[u8]) -> Option<u32> {
+  let mut r = head;
+  let mut id = None;
+  loop {
+    match frame::read_skippable(&mut r) {
+      Ok(Some((SETTINGS_MAGIC, data))) => id = serde_json::from_slice::<Settings>(&data).ok().and_then(|s| s.dict_id),
+      Ok(Some(_)) => (),
+      Ok(None) => return id.or(FrameHeader::parse(&head[head.len() - r.len() - 4..]).and_then(|h| h.dict_id)),
+      Err(_) => return id,
+    }
+  }
+}
+
+/// The contents of the stream R read from SRC, decrypted with the
+/// secret in OPTS if it is encrypted and decompressed, with the format
+/// it was compressed with. Zip archives and anything else are returned
+/// as they are.
+pub(crate) fn decode(src: &Path, r: Box<dyn Read>, opts: &UnpackOptions) -> Result<(Box<dyn Read>, Format)> {
+  let (head, r) = peek(r, 64 << 10)?;
+  let format = Format::detect(&head);
+  let r = io::BufReader::new(r);
+  let r: Box<dyn Read> = match format {
+    Format::Encrypted => match &opts.secret {
+      Some(secret) => return decode(src, Box::new(crypt::Reader::new(r, secret)?), opts),
+      None => return Err(Error::Encrypted(src.to_path_buf())),
+    },
+    Format::Zstd => Box::new(zstd::Decoder::with_dictionary(r, dict::find(opts.dicts(), dict_id(&head))?)?),
+    // all members of concatenated streams, as the command line tools do
+    Format::Gzip => Box::new(flate2::read::MultiGzDecoder::new(r)),
+    Format::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(r)),
+    Format::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(r)),
+    Format::Zip | Format::Tar | Format::Raw => Box::new(r),
+  };
+  Ok((r, format))
+}
+
+/// Open SRC according to its format, with the dictionaries and secret
+/// in OPTS. SRC `-` reads standard input, which can't be a zip archive
+/// since those are read from the end.
+pub fn open<P: AsRef<Path>>(src: P, opts: &UnpackOptions) -> Result<Source> {
+  let src = src.as_ref();
+  if !volume::is_stdio(src) && detect(src, opts)? == Format::Zip {
+    return Ok(Source::Zip(zip::ZipArchive::new(crypt::open(src, opts.secret.as_ref())?)?))
+  }
+  let (r, format) = decode(src, volume::stream(src)?, opts)?;
+  if format == Format::Zip {
+    return Err(Error::Io(io::Error::new(io::ErrorKind::Unsupported, "zip archives can't be read from standard input")))
+  }
+  // look for a tar header
+  let (head, r) = peek(r, 512)?;
+  if is_tar_header(&head) {
+    Ok(Source::Tar(r))
+  } else {
+    Ok(Source::Data(r, format))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Write;
+  #[test]
+  fn detect_formats() {
+    let mut tar = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_cksum();
+    tar.append_data(&mut header, "a.txt", &b"hello"[..]).unwrap();
+    let tar = tar.into_inner().unwrap();
+    assert!(is_tar_header(&tar));
+    assert!(!is_tar_header(&[0u8; 512]));
+
+    let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+    gz.write_all(&tar).unwrap();
+    let gz = gz.finish().unwrap();
+    let (mut xz, mut bz) = (vec![], vec![]);
+    xz2::read::XzEncoder::new(&tar[..], 1).read_to_end(&mut xz).unwrap();
+    bzip2::read::BzEncoder::new(&tar[..], bzip2::Compression::fast()).read_to_end(&mut bz).unwrap();
+    let zst = zstd::encode_all(&tar[..], 1).unwrap();
+    assert_eq!(Format::detect(&gz), Format::Gzip);
+    assert_eq!(Format::detect(&xz), Format::Xz);
+    assert_eq!(Format::detect(&bz), Format::Bzip2);
+    assert_eq!(Format::detect(&zst), Format::Zstd);
+    assert_eq!(Format::detect(&tar), Format::Tar);
+    assert_eq!(Format::detect(b"PK\x03\x04"), Format::Zip);
+    assert_eq!(Format::detect(b"hello"), Format::Raw);
+
+    // detected by content, whatever the name
+    let tmp = std::env::temp_dir().join("mtz_formats");
+    let _ = std::fs::remove_dir_all(&tmp);
+    std::fs::create_dir_all(&tmp).unwrap();
+    for (data, name) in [(&gz, "a.bin"), (&xz, "b"), (&bz, "c.tar.gz")] {
+      std::fs::write(tmp.join(name), data).unwrap();
+      let Source::Tar(r) = open(tmp.join(name), &UnpackOptions::new()).unwrap() else { panic!("expected a tarball") };
+      let mut archive = tar::Archive::new(r);
+      let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+      let mut s = String::new();
+      entry.read_to_string(&mut s).unwrap();
+      assert_eq!(s, "hello");
+    }
+    // streams, as read from standard input, with the dictionary found
+    // from the settings
+    let samples: Vec<u8> = (0..200u32).flat_map(|i| (0..300u32).map(move |j| ((j * j + i) % 97) as u8)).collect();
+    let dict = crate::dict::Dictionary::new(zstd::dict::from_continuous(&samples, &[300; 200], 2048).unwrap()).unwrap();
+    let mut zst = vec![];
+    let opts = crate::Options::new().dict(Some(dict.clone()));
+    let mut w = opts.encoder(&mut zst).unwrap();
+    w.write_all(&tar).unwrap();
+    w.finish().unwrap();
+    assert_eq!(dict_id(&zst), Some(dict.id()));
+    assert!(decode(Path::new("-"), Box::new(io::Cursor::new(zst.clone())), &UnpackOptions::new()).is_err());
+    let (mut r, format) = decode(Path::new("-"), Box::new(io::Cursor::new(zst)), &UnpackOptions::new().dict(dict)).unwrap();
+    let mut out = vec![];
+    r.read_to_end(&mut out).unwrap();
+    assert_eq!((out, format), (tar, Format::Zstd));
+
+    std::fs::write(tmp.join("d.gz"), &gz[..20]).unwrap();
+    assert!(open(tmp.join("d.gz"), &UnpackOptions::new()).is_err());
+    std::fs::remove_dir_all(&tmp).unwrap();
+  }
+  #[test]
+  fn zip_pack() {
+    use crate::{repack, safe::Reason, unpack, verify, Options};
+    use std::fs;
+    // a zip sample pack, unpacked with the same rules and repacked
+    let tmp = std::env::temp_dir().join("mtz_zip_pack");
+    let _ = fs::remove_dir_all(&tmp);
+    fs::create_dir_all(&tmp).unwrap();
+    let spec = hound::WavSpec { channels: 2, sample_rate: 44100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    let mut w = hound::WavWriter::create(tmp.join("tone.wav"), spec).unwrap();
+    for i in 0..50000 {
+      let s = ((i as f64 / 40.0).sin() * 10000.0) as i16;
+      w.write_sample(s).unwrap();
+      w.write_sample(s / 3).unwrap();
+    }
+    w.finalize().unwrap();
+    let tone = fs::read(tmp.join("tone.wav")).unwrap();
+    let mut zip = zip::ZipWriter::new(io::Cursor::new(vec![]));
+    let zo = zip::write::SimpleFileOptions::default();
+    zip.add_directory("pack/", zo).unwrap();
+    zip.start_file("pack/tone.wav", zo).unwrap();
+    zip.write_all(&tone).unwrap();
+    zip.start_file("../evil.txt", zo).unwrap();
+    zip.add_symlink("pack/link", "../../etc/passwd", zo).unwrap();
+    // misnamed, detected by content
+    let zipped = tmp.join("pack.bin");
+    fs::write(&zipped, zip.finish().unwrap().into_inner()).unwrap();
+    let res = unpack(&zipped, &tmp.join("out"), &UnpackOptions::new()).unwrap();
+    assert_eq!(res.extracted.len(), 2);
+    let reasons: Vec<_> = res.skipped.iter().map(|s| &s.reason).collect();
+    assert_eq!(reasons, [&Reason::Traversal, &Reason::LinkOutside("../../etc/passwd".into())]);
+    assert_eq!(fs::read(tmp.join("out/pack/tone.wav")).unwrap(), tone);
+    let repacked = tmp.join("pack.tar.zst");
+    assert_eq!(repack(&zipped, &repacked, &Options::new().audio(true)).unwrap()[0].reason, Reason::Traversal);
+    let v = verify(&repacked, &UnpackOptions::new()).unwrap();
+    assert!(v.manifest && v.problems.is_empty() && v.entries == 3);
+    assert_eq!(verify(&zipped, &UnpackOptions::new()).unwrap().entries, 4);
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+  #[test]
+  fn gzip() {
+    use crate::{list, unpack};
+    use std::fs;
+    // a gzip tarball and a gzip file
+    let tmp = std::env::temp_dir().join("mtz_gzip");
+    let _ = fs::remove_dir_all(&tmp);
+    let music = tmp.join("music");
+    fs::create_dir_all(&music).unwrap();
+    let spec = hound::WavSpec { channels: 2, sample_rate: 44100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    let mut w = hound::WavWriter::create(music.join("tone.wav"), spec).unwrap();
+    for i in 0..50000 {
+      let s = ((i as f64 / 40.0).sin() * 10000.0) as i16;
+      w.write_sample(s).unwrap();
+      w.write_sample(s / 3).unwrap();
+    }
+    w.finalize().unwrap();
+    fs::write(music.join("fake.wav"), b"not audio").unwrap();
+    let uo = UnpackOptions::new();
+    let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+    {
+      let mut t = tar::Builder::new(&mut gz);
+      t.append_dir_all("music", &music).unwrap();
+      t.finish().unwrap();
+    }
+    fs::write(tmp.join("music.tgz"), gz.finish().unwrap()).unwrap();
+    unpack(&tmp.join("music.tgz"), &tmp.join("out"), &uo).unwrap();
+    assert_eq!(fs::read(tmp.join("out/music/fake.wav")).unwrap(), b"not audio");
+    let mut names = vec![];
+    list(tmp.join("music.tgz"), &uo, |e| names.push(e.path.clone())).unwrap();
+    assert!(names.contains(&"music/tone.wav".into()));
+    let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+    gz.write_all(b"plain").unwrap();
+    fs::write(tmp.join("note.txt.gz"), gz.finish().unwrap()).unwrap();
+    unpack(&tmp.join("note.txt.gz"), &tmp.join("out"), &uo).unwrap();
+    assert_eq!(fs::read(tmp.join("out/note.txt")).unwrap(), b"plain");
+    assert!(unpack(&music.join("fake.wav"), &tmp.join("out"), &uo).is_err());
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+}
@@ -0,0 +1,122 @@
+//! frame --- zstd frame headers and skippable frames
+//!
+//! See [https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md]
+use crate::{Error, Result};
+use std::io::{self, Read, Write};
+
+pub const ZSTD_MAGIC: u32 = 0xFD2FB528;
+/// Skippable frames use magic numbers 0x184D2A50..=0x184D2A5F.
+pub const SKIPPABLE_MAGIC: u32 = 0x184D2A50;
+pub const SKIPPABLE_MASK: u32 = 0xFFFFFFF0;
+
+/// Fields of a zstd frame header.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FrameHeader {
+  pub window_size: Option<u64>,
+  pub dict_id: Option<u32>,
+  pub content_size: Option<u64>,
+  pub checksum: bool,
+}
+
+impl FrameHeader {
+  /// Parse the frame header at the start of BYTES. Returns None if
+  /// BYTES doesn't start with a zstd frame.
+  pub fn parse(bytes: &[u8]) -> Option<Self> {
+    if bytes.len() < 5 || u32::from_le_bytes(bytes[0..4].try_into().unwrap()) != ZSTD_MAGIC {
+      return None
+    }
+    let fhd = bytes[4];
+    let fcs_flag = fhd >> 6;
+    let single_segment = (fhd >> 5) & 1 == 1;
+    let checksum = (fhd >> 2) & 1 == 1;
+    let did_size = [0, 1, 2, 4][(fhd & 3) as usize];
+    let fcs_size = match fcs_flag {
+      0 if single_segment => 1,
+      0 => 0,
+      1 => 2,
+      2 => 4,
+      _ => 8,
+    };
+    let mut pos = 5;
+    let mut window_size = None;
+    if !single_segment {
+      let wd = *bytes.get(pos)?;
+      let log = 10 + (wd >> 3) as u32;
+      let base = 1u64 << log;
+      window_size = Some(base + (base / 8) * (wd & 7) as u64);
+      pos += 1;
+    }
+    let le = |b: &[u8]| b.iter().rev().fold(0u64, |n, b| (n << 8) | *b as u64);
+    let dict_id = match did_size {
+      0 => None,
+      n => Some(le(bytes.get(pos..pos + n)?) as u32),
+    };
+    pos += did_size;
+    let content_size = match fcs_size {
+      0 => None,
+      2 => Some(le(bytes.get(pos..pos + 2)?) + 256),
+      n => Some(le(bytes.get(pos..pos + n)?)),
+    };
+    if single_segment {
+      window_size = content_size;
+    }
+    Some(FrameHeader { window_size, dict_id, content_size, checksum })
+  }
+}
+
+/// Write a skippable frame containing DATA.
+pub fn write_skippable<W: Write>(w: &mut W, magic: u32, data: &[u8]) -> io::Result<()> {
+  w.write_all(&magic.to_le_bytes())?;
+  w.write_all(&(data.len() as u32).to_le_bytes())?;
+  w.write_all(data)
+}
+
+/// Read a skippable frame from R, returning its magic number and data.
+/// Returns None if R starts with something other than a skippable frame,
+/// in which case the first 4 bytes have been consumed.
+pub fn read_skippable<R: Read>(r: &mut R) -> Result<Option<(u32, Vec<u8>)>> {
+  let mut buf = [0u8; 4];
+  r.read_exact(&mut buf)?;
+  let magic = u32::from_le_bytes(buf);
+  if magic & SKIPPABLE_MASK != SKIPPABLE_MAGIC {
+    return Ok(None)
+  }
+  r.read_exact(&mut buf)?;
+  let len = u32::from_le_bytes(buf) as usize;
+  // the length is untrusted, so the data is only buffered as it arrives
+  let mut data = vec![];
+  r.take(len as u64).read_to_end(&mut data)?;
+  if data.len() != len {
+    return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated skippable frame")))
+  }
+  Ok(Some((magic, data)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn header() {
+    let data = vec![1u8; 1000];
+    let mut enc = zstd::Encoder::new(vec![], 3).unwrap();
+    enc.include_checksum(true).unwrap();
+    enc.set_pledged_src_size(Some(1000)).unwrap();
+    enc.write_all(&data).unwrap();
+    let frame = enc.finish().unwrap();
+    let header = FrameHeader::parse(&frame).unwrap();
+    assert_eq!(header.content_size, Some(1000));
+    assert_eq!(header.dict_id, None);
+    assert!(header.checksum);
+    assert!(FrameHeader::parse(b"nope!").is_none());
+
+    let mut skip = vec![];
+    write_skippable(&mut skip, SKIPPABLE_MAGIC + 1, b"abc").unwrap();
+    let (magic, data) = read_skippable(&mut &skip[..]).unwrap().unwrap();
+    assert_eq!((magic, data), (SKIPPABLE_MAGIC + 1, b"abc".to_vec()));
+    assert!(read_skippable(&mut &frame[..]).unwrap().is_none());
+    // a huge length with nothing after it
+    let mut huge = (SKIPPABLE_MAGIC + 2).to_le_bytes().to_vec();
+    huge.extend(u32::MAX.to_le_bytes());
+    assert!(read_skippable(&mut &huge[..]).is_err());
+  }
+}
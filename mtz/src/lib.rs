@@ -0,0 +1,1243 @@
+//! mtz --- package/unpackage a directory in tar.zst format
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::Path};
+use std::io::{Read, Write};
+
+mod err;
+pub use err::{Error, Result};
+pub mod frame;
+use frame::FrameHeader;
+pub mod manifest;
+use manifest::{HashReader, Manifest, Problem, MANIFEST_NAME};
+pub mod seekable;
+use seekable::{SeekTable, SeekableWriter};
+pub mod safe;
+pub mod exclude;
+use exclude::Excludes;
+pub mod volume;
+use volume::{Input, Output};
+pub mod dict;
+use dict::Dictionary;
+pub mod flac;
+pub mod audio;
+pub mod formats;
+use formats::{Format, Source};
+pub mod crypt;
+use crypt::Secret;
+pub mod meta;
+use meta::Records;
+pub use safe::{Overwrite, Skipped, Symlinks, Unpacked, UnpackOptions};
+use std::collections::BTreeMap;
+
+/// Magic number of the skippable frame recording the settings an
+/// archive was created with.
+pub const SETTINGS_MAGIC: u32 = frame::SKIPPABLE_MAGIC | 0xD;
+/// Window size (as a power of 2) used in long mode. Archives made with
+/// larger windows need special flags to decode with the zstd CLI.
+pub const LONG_WINDOW_LOG: u32 = 27;
+
+/// Level of compression data should be compressed with.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug)]
+pub enum Level {
+  /// Fastest quality of compression, usually produces bigger size.
+  Fastest,
+  /// Best quality of compression, usually produces the smallest size.
+  Best,
+  /// Default quality of compression defined by the selected compression
+  /// algorithm.
+  Default,
+  /// Precise quality based on the underlying compression algorithms'
+  /// qualities. The interpretation of this depends on the algorithm chosen
+  /// and the specific implementation backing it.
+  /// Qualities are implicitly clamped to the algorithm's maximum.
+  Precise(u8),
+}
+
+impl Level {
+  pub fn into_zstd(self) -> i32 {
+    match self {
+      Self::Fastest => 1,
+      Self::Best => 21,
+      Self::Precise(quality) => quality.min(21) as i32,
+      Self::Default => 0,
+    }
+  }
+}
+
+impl From<u8> for Level {
+  fn from(n: u8) -> Self {
+    match n {
+      0 => Self::Default,
+      1 => Self::Fastest,
+      2 => Self::Best,
+      n => Self::Precise(n),
+    }
+  }
+}
+
+/// Parse a level name (fastest, best, default) or number.
+impl std::str::FromStr for Level {
+  type Err = std::num::ParseIntError;
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "fastest" => Ok(Self::Fastest),
+      "best" => Ok(Self::Best),
+      "default" => Ok(Self::Default),
+      n => n.parse::<u8>().map(Level::from),
+    }
+  }
+}
+
+/// Predefined sets of options.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Preset {
+  /// Best for large audio/media archives: high level with long-distance
+  /// matching over a 128MB window.
+  Audio,
+}
+
+impl std::str::FromStr for Preset {
+  type Err = Error;
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_lowercase().as_str() {
+      "audio" => Ok(Preset::Audio),
+      _ => Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown preset: {}. valid = [audio]", s)))),
+    }
+  }
+}
+
+/// How entry modification times are stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mtime {
+  /// The modification time of each file.
+  #[default]
+  Keep,
+  /// The same time, in seconds since the epoch, for every entry.
+  Fixed(u64),
+  /// The modification time of each file, but no later than the given
+  /// time, as with SOURCE_DATE_EPOCH.
+  Clamp(u64),
+}
+
+impl Mtime {
+  pub fn apply(self, mtime: u64) -> u64 {
+    match self {
+      Mtime::Keep => mtime,
+      Mtime::Fixed(t) => t,
+      Mtime::Clamp(t) => mtime.min(t),
+    }
+  }
+}
+
+/// Compression options for pack and compress.
+#[derive(Clone, Debug)]
+pub struct Options {
+  level: Level,
+  threads: u32,
+  long: bool,
+  seekable: bool,
+  excludes: Vec<String>,
+  default_excludes: bool,
+  reproducible: bool,
+  mtime: Mtime,
+  split: Option<u64>,
+  base: Option<Manifest>,
+  dict: Option<Dictionary>,
+  audio: bool,
+  encrypt: Option<Secret>,
+  xattrs: bool,
+  precise_mtime: bool,
+}
+
+impl Default for Options {
+  fn default() -> Self {
+    Options::new()
+  }
+}
+
+impl Options {
+  pub fn new() -> Self {
+    Options {
+      level: Level::Default,
+      threads: 0,
+      long: false,
+      seekable: false,
+      excludes: vec![],
+      default_excludes: true,
+      reproducible: false,
+      mtime: Mtime::Keep,
+      split: None,
+      base: None,
+      dict: None,
+      audio: false,
+      encrypt: None,
+      xattrs: false,
+      precise_mtime: false,
+    }
+  }
+  pub fn level(mut self, level: Level) -> Self {
+    self.level = level;
+    self
+  }
+  /// Number of zstd worker threads. 0 compresses on the calling thread.
+  pub fn threads(mut self, threads: u32) -> Self {
+    self.threads = threads;
+    self
+  }
+  /// Enable long-distance matching with a window of 2^LONG_WINDOW_LOG
+  /// bytes, which finds repeats far apart in big media archives.
+  pub fn long(mut self, long: bool) -> Self {
+    self.long = long;
+    self
+  }
+  /// Write archives as independent frames with a seek table, so single
+  /// entries can be extracted without decompressing the whole archive.
+  /// Costs some compression, since each entry is compressed on its own.
+  pub fn seekable(mut self, seekable: bool) -> Self {
+    self.seekable = seekable;
+    self
+  }
+  /// Leave out files matching PATTERN (gitignore syntax) when packing.
+  /// Patterns are also read from .mtzignore files in the packed tree.
+  pub fn exclude(mut self, pattern: &str) -> Self {
+    self.excludes.push(pattern.to_string());
+    self
+  }
+  /// Leave out common junk such as .DS_Store and target/ directories.
+  /// See `exclude::DEFAULT_EXCLUDES`.
+  pub fn default_excludes(mut self, default_excludes: bool) -> Self {
+    self.default_excludes = default_excludes;
+    self
+  }
+  /// Make identical trees produce identical archives: owners are
+  /// stored as uid/gid 0 with no names, permissions as 0755 or 0644
+  /// (keeping the owner execute bit), and the manifest gets a fixed
+  /// mtime. Entries are always stored in file name order.
+  pub fn reproducible(mut self, reproducible: bool) -> Self {
+    self.reproducible = reproducible;
+    self
+  }
+  pub fn mtime(mut self, mtime: Mtime) -> Self {
+    self.mtime = mtime;
+    self
+  }
+  /// Write the output as numbered volumes of at most SIZE bytes, such
+  /// as NAME.tar.zst.001, NAME.tar.zst.002, ...
+  pub fn split(mut self, size: Option<u64>) -> Self {
+    self.split = size;
+    self
+  }
+  /// Pack only the files which are new or changed since the archive
+  /// with manifest BASE, and record the files deleted since. See
+  /// `unpack_chain`.
+  pub fn base(mut self, base: Option<Manifest>) -> Self {
+    self.base = base;
+    self
+  }
+  /// Compress with DICT, which must then be given to decompress. Helps
+  /// with collections of small files compressed one by one.
+  pub fn dict(mut self, dict: Option<Dictionary>) -> Self {
+    self.dict = dict;
+    self
+  }
+  /// Store the samples of WAV and AIFF files as FLAC, which shrinks
+  /// PCM audio far more than zstd. Other chunks are kept as they are,
+  /// and unpack restores the original files byte for byte.
+  pub fn audio(mut self, audio: bool) -> Self {
+    self.audio = audio;
+    self
+  }
+  /// Store the extended attributes of files and directories. See
+  /// `meta`.
+  pub fn xattrs(mut self, xattrs: bool) -> Self {
+    self.xattrs = xattrs;
+    self
+  }
+  /// Store modification times with nanoseconds, as well as in whole
+  /// seconds in the headers. See `meta`.
+  pub fn precise_mtime(mut self, precise_mtime: bool) -> Self {
+    self.precise_mtime = precise_mtime;
+    self
+  }
+  /// Encrypt the output with a key derived from SECRET. See `crypt`.
+  pub fn encrypt(mut self, secret: Option<Secret>) -> Self {
+    self.encrypt = secret;
+    self
+  }
+  pub fn preset(self, preset: Preset) -> Self {
+    match preset {
+      Preset::Audio => self.level(Level::Precise(19)).long(true),
+    }
+  }
+
+  /// The settings recorded in archives created with these options.
+  pub fn settings(&self) -> Settings {
+    Settings {
+      version: 1,
+      level: match self.level.into_zstd() {
+	0 => zstd::DEFAULT_COMPRESSION_LEVEL,
+	n => n,
+      },
+      threads: self.threads,
+      long: self.long,
+      window_log: if self.long { Some(LONG_WINDOW_LOG) } else { None },
+      seekable: self.seekable,
+      reproducible: self.reproducible,
+      split: self.split,
+      dict_id: self.dict.as_ref().map(|d| d.id()),
+      audio: self.audio,
+      xattrs: self.xattrs,
+      precise_mtime: self.precise_mtime,
+    }
+  }
+
+  /// Create an encoder writing to W, starting with a skippable frame
+  /// holding the settings.
+  pub fn encoder<'a, W: Write>(&self, mut w: W) -> Result<zstd::Encoder<'a, W>> {
+    let settings = serde_json::to_vec(&self.settings()).map_err(io::Error::from)?;
+    frame::write_skippable(&mut w, SETTINGS_MAGIC, &settings)?;
+    Ok(self.frame_encoder(w)?)
+  }
+
+  /// Create an encoder for a single frame, without the settings.
+  pub fn frame_encoder<'a, W: Write>(&self, w: W) -> io::Result<zstd::Encoder<'a, W>> {
+    let mut encoder = match &self.dict {
+      Some(dict) => zstd::Encoder::with_dictionary(w, self.level.into_zstd(), dict.as_bytes())?,
+      None => zstd::Encoder::new(w, self.level.into_zstd())?,
+    };
+    encoder.include_checksum(true)?;
+    if self.threads > 0 {
+      encoder.multithread(self.threads)?;
+    }
+    if self.long {
+      encoder.long_distance_matching(true)?;
+      encoder.window_log(LONG_WINDOW_LOG)?;
+    }
+    Ok(encoder)
+  }
+}
+
+/// Compression settings stored at the start of an archive.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+  pub version: u32,
+  pub level: i32,
+  pub threads: u32,
+  pub long: bool,
+  pub window_log: Option<u32>,
+  #[serde(default)]
+  pub seekable: bool,
+  #[serde(default)]
+  pub reproducible: bool,
+  /// Volume size of split archives.
+  #[serde(default)]
+  pub split: Option<u64>,
+  /// ID of the dictionary needed to decompress.
+  #[serde(default)]
+  pub dict_id: Option<u32>,
+  /// Whether WAV and AIFF files are stored as FLAC.
+  #[serde(default)]
+  pub audio: bool,
+  /// Whether extended attributes are stored.
+  #[serde(default)]
+  pub xattrs: bool,
+  /// Whether mtimes are stored with nanoseconds.
+  #[serde(default)]
+  pub precise_mtime: bool,
+}
+
+/// Output of pack and compress: a single zstd stream, or independent
+/// frames with a seek table.
+pub enum Sink<'a, W: Write> {
+  Stream(zstd::Encoder<'a, W>),
+  Seekable(Box<SeekableWriter<'a, W>>),
+}
+
+impl<'a, W: Write> Sink<'a, W> {
+  pub fn new(w: W, opts: &Options) -> Result<Self> {
+    if opts.seekable {
+      Ok(Sink::Seekable(Box::new(SeekableWriter::new(w, opts)?)))
+    } else {
+      Ok(Sink::Stream(opts.encoder(w)?))
+    }
+  }
+  /// Record the start of the entry at PATH. Starts a new frame in
+  /// seekable mode.
+  pub fn mark(&mut self, path: &Path) -> io::Result<()> {
+    match self {
+      Sink::Stream(_) => Ok(()),
+      Sink::Seekable(w) => w.mark(&path.to_string_lossy()),
+    }
+  }
+  pub fn finish(self) -> Result<W> {
+    match self {
+      Sink::Stream(e) => Ok(e.finish()?),
+      Sink::Seekable(w) => w.finish(),
+    }
+  }
+}
+
+impl<W: Write> Write for Sink<'_, W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self {
+      Sink::Stream(e) => e.write(buf),
+      Sink::Seekable(w) => w.write(buf),
+    }
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      Sink::Stream(e) => e.flush(),
+      Sink::Seekable(w) => w.flush(),
+    }
+  }
+}
+
+/// Create the output at DST for OPTS, split and encrypted as they set.
+fn output(dst: &Path, opts: &Options) -> Result<Output> {
+  let out = Output::create(dst, opts.split)?;
+  match &opts.encrypt {
+    Some(secret) => out.encrypt(secret),
+    None => Ok(out),
+  }
+}
+
+/// Information about a compressed file, read from its first frames.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Info {
+  pub size: u64,
+  pub format: Format,
+  pub encrypted: bool,
+  pub settings: Option<Settings>,
+  pub header: Option<FrameHeader>,
+  /// Number of frames in the seek table, for seekable files.
+  pub frames: Option<usize>,
+}
+
+/// Read the settings and first frame header of a compressed file.
+/// Encrypted files are read with the secret in OPTS, and without one
+/// only their size and format are known.
+pub fn info<P: AsRef<Path>>(src: P, opts: &UnpackOptions) -> Result<Info> {
+  let src = src.as_ref();
+  let format = formats::detect(src, opts)?;
+  let size = Input::open(src)?.len();
+  let input = crypt::open(src, opts.secret.as_ref());
+  if let Err(Error::Encrypted(_)) = input {
+    return Ok(Info { size, format, encrypted: true, settings: None, header: None, frames: None })
+  }
+  let input = input?;
+  let encrypted = matches!(input, crypt::Plain::Decrypted(_));
+  let mut file = io::BufReader::new(input);
+  let mut settings = None;
+  let mut head = vec![];
+  // skip leading skippable frames, keeping our settings if present
+  loop {
+    match frame::read_skippable(&mut file)? {
+      Some((SETTINGS_MAGIC, data)) => settings = serde_json::from_slice(&data).ok(),
+      Some(_) => (),
+      None => break,
+    }
+  }
+  file.seek_relative(-4)?;
+  (&mut file).take(18).read_to_end(&mut head)?;
+  let frames = SeekTable::read(file.get_mut())?.map(|t| t.frames.len());
+  Ok(Info { size, format, encrypted, settings, header: FrameHeader::parse(&head), frames })
+}
+
+/// Metadata of a single archive entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntryInfo {
+  pub path: std::path::PathBuf,
+  pub kind: tar::EntryType,
+  pub size: u64,
+  pub mode: u32,
+  pub mtime: u64,
+}
+
+/// Result of verifying an archive. PROBLEMS lists files which don't
+/// match the manifest, if the archive has one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Verified {
+  pub entries: u64,
+  pub bytes: u64,
+  pub manifest: bool,
+  pub problems: Vec<Problem>,
+}
+
+/// The dictionary among those in OPTS needed to decompress SRC, or an
+/// empty one if SRC was compressed without a dictionary.
+fn find_dict<'a>(src: &Path, opts: &'a UnpackOptions) -> Result<&'a [u8]> {
+  let info = info(src, opts)?;
+  let id = info.settings.and_then(|s| s.dict_id).or(info.header.and_then(|h| h.dict_id));
+  dict::find(opts.dicts(), id)
+}
+
+/// Open a compressed file, or the volumes of a split one, for streaming
+/// decompression with the dictionary and secret in OPTS.
+fn decoder(src: &Path, opts: &UnpackOptions) -> Result<zstd::Decoder<'static, io::BufReader<crypt::Plain>>> {
+  let dict = find_dict(src, opts)?;
+  Ok(zstd::Decoder::with_dictionary(io::BufReader::new(crypt::open(src, opts.secret.as_ref())?), dict)?)
+}
+
+/// Metadata of ENTRY, with the original size of encoded audio files.
+fn entry_info<R: Read>(entry: &mut tar::Entry<R>) -> Result<EntryInfo> {
+  let size = audio::encoded_size(entry)?;
+  let header = entry.header();
+  Ok(EntryInfo {
+    path: entry.path()?.to_path_buf(),
+    kind: header.entry_type(),
+    size: match size {
+      Some(size) => size,
+      None => header.size()?,
+    },
+    mode: header.mode()?,
+    mtime: header.mtime()?,
+  })
+}
+
+fn zip_entry_info(file: &zip::read::ZipFile<'_>) -> EntryInfo {
+  let kind = match (file.is_dir(), file.is_symlink()) {
+    (true, _) => tar::EntryType::Directory,
+    (_, true) => tar::EntryType::Symlink,
+    _ => tar::EntryType::Regular,
+  };
+  let mode = file.unix_mode().unwrap_or(if file.is_dir() { 0o755 } else { 0o644 });
+  EntryInfo { path: file.name().into(), kind, size: file.size(), mode: mode & 0o7777, mtime: safe::zip_mtime(file) }
+}
+
+fn not_archive(src: &Path) -> Error {
+  Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("not an archive: {}", src.display())))
+}
+
+/// Call F with the metadata of each entry of an archive (tar, possibly
+/// compressed, or zip), in archive order, without extracting anything.
+pub fn list<P: AsRef<Path>, F: FnMut(&EntryInfo)>(src: P, opts: &UnpackOptions, mut f: F) -> Result<()> {
+  match formats::open(src.as_ref(), opts)? {
+    Source::Tar(r) => {
+      for entry in tar::Archive::new(r).entries()? {
+	f(&entry_info(&mut entry?)?);
+      }
+    },
+    Source::Zip(mut zip) => {
+      for i in 0..zip.len() {
+	f(&zip_entry_info(&zip.by_index(i)?));
+      }
+    },
+    Source::Data(..) => return Err(not_archive(src.as_ref())),
+  }
+  Ok(())
+}
+
+/// Decode a whole compressed file, checking the checksums of its
+/// format, and for archives read every entry to the end.
+pub fn verify<P: AsRef<Path>>(src: P, opts: &UnpackOptions) -> Result<Verified> {
+  let src = src.as_ref();
+  let mut res = Verified::default();
+  match formats::open(src, opts)? {
+    Source::Tar(r) => verify_tar(r, &mut res)?,
+    Source::Zip(mut zip) => {
+      // reading an entry to the end checks its CRC
+      for i in 0..zip.len() {
+	res.entries += 1;
+	res.bytes += io::copy(&mut zip.by_index(i)?, &mut io::sink())?;
+      }
+    },
+    Source::Data(_, Format::Raw) => return Err(unknown_format(src)),
+    Source::Data(mut r, _) => res.bytes = io::copy(&mut r, &mut io::sink())?,
+  }
+  Ok(res)
+}
+
+fn unknown_format(src: &Path) -> Error {
+  Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("not a compressed file or archive: {}", src.display())))
+}
+
+fn verify_tar(r: Box<dyn Read>, res: &mut Verified) -> Result<()> {
+  let mut archive = tar::Archive::new(r);
+  let mut hashes = BTreeMap::new();
+  let mut manifest = None;
+  for entry in archive.entries()? {
+    let mut entry = entry?;
+    let path = entry.path()?.to_string_lossy().to_string();
+    if path == MANIFEST_NAME {
+      manifest = Some(read_manifest(&mut entry)?);
+      continue
+    }
+    res.entries += 1;
+    // audio files are hashed as restored
+    let hash = if audio::encoded_size(&mut entry)?.is_some() {
+      let mut hasher = blake3::Hasher::new();
+      let size = audio::decode(&mut entry, &mut hasher)?;
+      res.bytes += size;
+      manifest::FileHash { size, blake3: hasher.finalize().to_hex().to_string() }
+    } else {
+      let mut reader = HashReader::new(&mut entry);
+      res.bytes += io::copy(&mut reader, &mut io::sink())?;
+      reader.finish()
+    };
+    hashes.insert(path, hash);
+  }
+  if let Some(manifest) = manifest {
+    res.manifest = true;
+    res.problems = manifest.compare(&hashes);
+  }
+  // consume the rest of the stream so all checksums are checked
+  io::copy(&mut archive.into_inner(), &mut io::sink())?;
+  Ok(())
+}
+
+/// Pack a SRC directory, and return a compressed archive at DST. The
+/// tarball is streamed through the encoder into DST, so memory use
+/// doesn't depend on the size of SRC. A file, or standard input for
+/// SRC `-`, is compressed instead. DST `-` writes to standard output.
+pub fn pack<P: AsRef<Path>>(src: P, dst: P, opts: &Options) -> Result<()> {
+  let src = src.as_ref();
+  let dst = dst.as_ref();
+  if !src.is_file() && !volume::is_stdio(src) {
+    if !src.exists() {
+      return Err(Error::NotFound(src.to_path_buf()))
+    }
+    let art = src.file_name().ok_or_else(|| Error::InvalidPath(src.to_path_buf()))?;
+    let mut tar = tar::Builder::new(Sink::new(output(dst, opts)?, opts)?);
+    let mut manifest = Manifest::new();
+    let mut excludes = Excludes::new(src, opts)?;
+    append_tree(&mut tar, src, Path::new(art), &mut manifest, &mut excludes, opts)?;
+    tar.get_mut().mark(Path::new(MANIFEST_NAME))?;
+    if let Some(base) = &opts.base {
+      manifest.set_base(base);
+    }
+    append_manifest(&mut tar, &manifest, manifest_mtime(opts))?;
+    tar.into_inner()?.finish()?.finish()
+  } else {
+    compress(src, dst, opts)
+  }
+}
+
+/// Append the tree at SRC to TAR under the path ART, in file name
+/// order, recording the hash of each regular file in MANIFEST. Symlinks
+/// are stored as links. Entries below SRC matching EXCLUDES are left
+/// out. Extended attributes and precise mtimes go in PAX records if
+/// OPTS ask for them.
+fn append_tree<W: Write>(
+  tar: &mut tar::Builder<Sink<W>>,
+  src: &Path,
+  art: &Path,
+  manifest: &mut Manifest,
+  excludes: &mut Excludes,
+  opts: &Options,
+) -> Result<()> {
+  let meta = fs::symlink_metadata(src)?;
+  let mut header = entry_header(&meta, opts);
+  let records = meta::records(src, &meta, opts)?;
+  tar.get_mut().mark(art)?;
+  if meta.is_dir() {
+    append_pax(tar, &records)?;
+    tar.append_data(&mut header, art, io::empty())?;
+    let mut names = fs::read_dir(src)?
+      .map(|e| e.map(|e| e.file_name()))
+      .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    let entered = excludes.enter(src)?;
+    for name in names {
+      let path = src.join(&name);
+      if excludes.is_excluded(&path, path.is_dir() && !path.is_symlink()) {
+	continue
+      }
+      append_tree(tar, &path, &art.join(&name), manifest, excludes, opts)?;
+    }
+    if entered {
+      excludes.leave();
+    }
+  } else if meta.file_type().is_symlink() {
+    append_pax(tar, &records)?;
+    tar.append_link(&mut header, art, fs::read_link(src)?)?;
+  } else if meta.is_file() {
+    // files of the same size as in the base are hashed first, and
+    // skipped if unchanged
+    let key = art.to_string_lossy().to_string();
+    if let Some(base) = opts.base.as_ref().and_then(|b| b.files.get(&key)).filter(|h| h.size == meta.len()) {
+      let hash = manifest::hash_reader(fs::File::open(src)?)?;
+      if hash == *base {
+	manifest.insert(art, hash);
+	manifest.unchanged.insert(key);
+	return Ok(())
+      }
+    }
+    append_file(tar, &mut header, art, fs::File::open(src)?, manifest, records, opts)?;
+  }
+  Ok(())
+}
+
+/// Append a PAX header with RECORDS to TAR, if there are any.
+fn append_pax<W: Write>(tar: &mut tar::Builder<W>, records: &[(String, Vec<u8>)]) -> Result<()> {
+  if !records.is_empty() {
+    let (header, data) = meta::pax_header(records);
+    tar.append(&header, &data[..])?;
+  }
+  Ok(())
+}
+
+/// Append the regular file at ART with HEADER, PAX RECORDS and contents
+/// R to TAR, recording its hash in MANIFEST. Audio files are stored as
+/// FLAC if OPTS ask for it.
+fn append_file<W: Write, R: Read>(
+  tar: &mut tar::Builder<W>,
+  header: &mut tar::Header,
+  art: &Path,
+  mut r: R,
+  manifest: &mut Manifest,
+  mut records: Records,
+  opts: &Options,
+) -> Result<()> {
+  if opts.audio && audio::is_audio(art) && header.size()? <= audio::MAX_SIZE {
+    let mut data = vec![];
+    r.read_to_end(&mut data)?;
+    manifest.insert(art, manifest::hash_reader(&data[..])?);
+    match audio::encode(&data) {
+      Some(encoded) => {
+	records.extend(audio::pax_records(data.len() as u64));
+	append_pax(tar, &records)?;
+	header.set_size(encoded.len() as u64);
+	tar.append_data(header, art, &encoded[..])?;
+      },
+      None => {
+	append_pax(tar, &records)?;
+	tar.append_data(header, art, &data[..])?;
+      },
+    }
+    return Ok(())
+  }
+  append_pax(tar, &records)?;
+  let mut reader = HashReader::new(r);
+  tar.append_data(header, art, &mut reader)?;
+  manifest.insert(art, reader.finish());
+  Ok(())
+}
+
+/// Modification time of the manifest entry: the current time, unless
+/// OPTS fix it.
+fn manifest_mtime(opts: &Options) -> u64 {
+  let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  match (opts.reproducible, opts.mtime) {
+    (_, Mtime::Fixed(t)) | (true, Mtime::Clamp(t)) => t,
+    (true, Mtime::Keep) => 0,
+    (false, m) => m.apply(now),
+  }
+}
+
+/// Header for an entry with metadata META, normalized as set by OPTS.
+fn entry_header(meta: &fs::Metadata, opts: &Options) -> tar::Header {
+  let mut header = tar::Header::new_gnu();
+  let mode = if opts.reproducible { tar::HeaderMode::Deterministic } else { tar::HeaderMode::Complete };
+  header.set_metadata_in_mode(meta, mode);
+  let mtime = meta.modified().ok()
+    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+    .map(|d| d.as_secs())
+    .unwrap_or(0);
+  header.set_mtime(opts.mtime.apply(mtime));
+  header
+}
+
+fn append_manifest<W: Write>(tar: &mut tar::Builder<W>, manifest: &Manifest, mtime: u64) -> Result<()> {
+  let data = manifest.to_vec()?;
+  let mut header = tar::Header::new_gnu();
+  header.set_size(data.len() as u64);
+  header.set_mode(0o644);
+  header.set_mtime(mtime);
+  tar.append_data(&mut header, MANIFEST_NAME, &data[..])?;
+  Ok(())
+}
+
+fn read_manifest<R: Read>(r: &mut R) -> Result<Manifest> {
+  let mut data = vec![];
+  r.read_to_end(&mut data)?;
+  Manifest::from_slice(&data)
+}
+
+/// unpack an archive or compressed file
+///
+/// Tarballs compressed with zstd, gzip, xz or bzip2, zip archives and
+/// plain tarballs are recognized by content, whatever their names. The
+/// archive is decoded in a single pass, streaming into the tar reader or
+/// target file. A compressed file which isn't a tarball is decompressed
+/// into the DST directory, without its last extension. Entries are
+/// checked against OPTS, and those which aren't extracted are listed in
+/// the result. SRC `-` reads standard input, and DST `-` decompresses
+/// to standard output.
+pub fn unpack<P: AsRef<Path>>(src: P, dst: P, opts: &UnpackOptions) -> Result<Unpacked> {
+  let src = src.as_ref();
+  let dst = dst.as_ref();
+  if volume::is_stdio(dst) {
+    return decompress(src, dst, opts)
+  }
+  let source = formats::open(src, opts)?;
+  if !matches!(source, Source::Data(..)) {
+    let mut res = Unpacked::default();
+    let manifest = unpack_archive(source, src, dst, opts, &mut res)?;
+    // unchanged files of an increment aren't in this archive
+    let unchanged = manifest.as_ref().map(|m| m.unchanged.clone()).unwrap_or_default();
+    check_manifest(manifest, dst, &res, |p| !unchanged.contains(p))?;
+    Ok(res)
+  } else if volume::is_stdio(src) {
+    Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, "standard input is not an archive (unpack to - to decompress it)")))
+  } else {
+    let input = Input::open(src)?;
+    let src = match volume::base_path(input.first()) {
+      Some(base) if !src.is_file() || src == input.first() => base,
+      _ => src.to_path_buf(),
+    };
+    // NAME.zst.enc decrypts to NAME
+    let plain = match src.extension() {
+      Some(ext) if ext == "enc" => src.with_extension(""),
+      _ => src.clone(),
+    };
+    let name = plain.file_stem().ok_or_else(|| Error::InvalidPath(src.to_path_buf()))?;
+    fs::create_dir_all(dst)?;
+    decompress(src.as_path(), dst.join(name).as_path(), opts)
+  }
+}
+
+/// Unpack the entries of SOURCE, the archive SRC, into DST, returning
+/// its manifest.
+fn unpack_archive(source: Source, src: &Path, dst: &Path, opts: &UnpackOptions, res: &mut Unpacked) -> Result<Option<Manifest>> {
+  let mut manifest = None;
+  fs::create_dir_all(dst)?;
+  match source {
+    Source::Tar(r) => {
+      for entry in tar::Archive::new(r).entries()? {
+	let mut entry = entry?;
+	if entry.path()?.as_os_str() == MANIFEST_NAME {
+	  manifest = Some(read_manifest(&mut entry)?);
+	} else {
+	  safe::unpack_entry(&mut entry, dst, opts, res)?;
+	}
+      }
+    },
+    Source::Zip(mut zip) => {
+      for i in 0..zip.len() {
+	safe::unpack_zip_entry(&mut zip.by_index(i)?, dst, opts, res)?;
+      }
+    },
+    Source::Data(..) => return Err(not_archive(src)),
+  }
+  safe::set_dir_times(res)?;
+  Ok(manifest)
+}
+
+/// Unpack a full archive followed by a chain of INCREMENTS, each packed
+/// against the one before, to rebuild the latest state in DST. Files of
+/// an increment replace existing ones, and files it lists as deleted are
+/// removed. The result is checked against the manifest of the last
+/// archive.
+pub fn unpack_chain<P: AsRef<Path>, Q: AsRef<Path>>(src: P, increments: &[Q], dst: P, opts: &UnpackOptions) -> Result<Unpacked> {
+  let dst = dst.as_ref();
+  let mut res = Unpacked::default();
+  let invalid = |msg: String| Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg));
+  let mut prev = unpack_archive(formats::open(src.as_ref(), opts)?, src.as_ref(), dst, opts, &mut res)?
+    .ok_or_else(|| invalid(format!("no manifest in {}", src.as_ref().display())))?;
+  for inc in increments {
+    let inc = inc.as_ref();
+    // check the link before changing anything
+    let next = manifest(inc, opts)?;
+    if next.base.as_deref() != Some(prev.state().as_str()) {
+      return Err(invalid(format!("{} is not an increment of the archive before it", inc.display())))
+    }
+    unpack_archive(formats::open(inc, opts)?, inc, dst, &opts.clone().overwrite(safe::Overwrite::Always), &mut res)?;
+    for path in &next.deleted {
+      let path = Path::new(path);
+      // links unpacked before may lead anywhere
+      if !safe::is_inside(path) || !safe::parent_inside(dst, path)? {
+	res.skip(path, safe::Reason::Traversal);
+	continue
+      }
+      match fs::remove_file(dst.join(path)) {
+	Ok(()) => res.deleted.push(path.to_path_buf()),
+	Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+	Err(e) => return Err(e.into()),
+      }
+    }
+    prev = next;
+  }
+  check_manifest(Some(prev), dst, &res, |_| true)?;
+  Ok(res)
+}
+
+/// Read the manifest of an archive, or a manifest saved as a .json
+/// file. Seekable archives only decompress the manifest entry.
+pub fn manifest<P: AsRef<Path>>(src: P, opts: &UnpackOptions) -> Result<Manifest> {
+  let src = src.as_ref();
+  if src.extension().is_some_and(|e| e == "json") {
+    return Manifest::from_slice(&fs::read(src)?)
+  }
+  let mut input = crypt::open(src, opts.secret.as_ref())?;
+  if let Some(table) = SeekTable::read(&mut input)? {
+    if let Some(pos) = table.index(&mut input)?.and_then(|i| i.get(MANIFEST_NAME).copied()) {
+      let mut archive = tar::Archive::new(table.reader_at(input, pos, find_dict(src, opts)?)?);
+      if let Some(entry) = archive.entries()?.next() {
+	return read_manifest(&mut entry?)
+      }
+    }
+  }
+  let mut archive = tar::Archive::new(decoder(src, opts)?);
+  for entry in archive.entries()? {
+    let mut entry = entry?;
+    if entry.path()?.as_os_str() == MANIFEST_NAME {
+      return read_manifest(&mut entry)
+    }
+  }
+  Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("no manifest in {}", src.display()))))
+}
+
+/// Check the files in DST against the entries of MANIFEST selected by
+/// F, leaving out entries which were skipped.
+fn check_manifest<F: Fn(&str) -> bool>(manifest: Option<Manifest>, dst: &Path, res: &Unpacked, f: F) -> Result<()> {
+  if let Some(mut manifest) = manifest {
+    // entries missing only extended attributes are still checked
+    let skipped = |p: &str| res.skipped.iter().any(|s| s.path == Path::new(p) && !matches!(s.reason, safe::Reason::Xattr(_)));
+    manifest.files.retain(|p, _| f(p) && !skipped(p));
+    let problems = manifest.check(dst);
+    if !problems.is_empty() {
+      return Err(Error::Manifest(problems))
+    }
+  }
+  Ok(())
+}
+
+/// unpack a tar.zst compressed archive, removing the source file before
+/// returning
+pub fn unpack_replace<P: AsRef<Path>>(src: P, dst: P, opts: &UnpackOptions) -> Result<Unpacked> {
+  let res = unpack(&src, &dst, opts)?;
+  fs::remove_file(src)?;
+  Ok(res)
+}
+
+/// compress a file with zstd, where `-` stands for standard input or
+/// output
+pub fn compress<P: AsRef<Path>>(
+  src: P,
+  dst: P,
+  opts: &Options,
+) -> Result<()> {
+  let mut file = volume::stream(src.as_ref())?;
+  let mut encoder = Sink::new(output(dst.as_ref(), opts)?, opts)?;
+  io::copy(&mut file, &mut encoder)?;
+  encoder.finish()?.finish()
+}
+
+/// Convert the archive SRC (a tarball compressed with any supported
+/// format, or a zip archive) into a tar.zst archive at DST compressed
+/// with OPTS, with a manifest of the files. Entries are copied as they
+/// are, and only mtimes are changed as set by OPTS. Entries with
+/// absolute paths or `..` components are left out, and returned. A
+/// compressed file which isn't an archive is recompressed with zstd.
+/// Input compressed with a zstd dictionary needs the same one in OPTS,
+/// and encrypted input the same secret, which also encrypts the output.
+pub fn repack<P: AsRef<Path>>(src: P, dst: P, opts: &Options) -> Result<Vec<Skipped>> {
+  let src = src.as_ref();
+  if fs::canonicalize(src).ok().is_some_and(|s| fs::canonicalize(dst.as_ref()).is_ok_and(|d| d == s)) {
+    return Err(Error::InvalidPath(dst.as_ref().to_path_buf()))
+  }
+  let read = opts.dict.iter().fold(UnpackOptions::new(), |o, d| o.dict(d.clone())).secret(opts.encrypt.clone());
+  let source = formats::open(src, &read)?;
+  let mut tar = tar::Builder::new(Sink::new(output(dst.as_ref(), opts)?, opts)?);
+  let mut manifest = Manifest::new();
+  let mut skipped = vec![];
+  let mut check = |path: &Path| match safe::check_path(path) {
+    Some(reason) => {
+      skipped.push(Skipped { path: path.to_path_buf(), reason });
+      false
+    },
+    None => true,
+  };
+  match source {
+    Source::Tar(r) => {
+      for entry in tar::Archive::new(r).entries()? {
+	let mut entry = entry?;
+	let path = entry.path()?.to_path_buf();
+	if path.as_os_str() == MANIFEST_NAME || !check(&path) {
+	  continue
+	}
+	let mut header = entry.header().clone();
+	header.set_mtime(opts.mtime.apply(header.mtime()?));
+	// a precise mtime is only kept as it is
+	let records = meta::copy_records(&mut entry, opts.mtime == Mtime::Keep)?;
+	tar.get_mut().mark(&path)?;
+	match entry.link_name()? {
+	  Some(target) => {
+	    append_pax(&mut tar, &records)?;
+	    tar.append_link(&mut header, &path, target)?
+	  },
+	  None if header.entry_type().is_file() => match audio::encoded_size(&mut entry)? {
+	    Some(size) => {
+	      let data = audio::decode_entry(&mut entry, size)?;
+	      header.set_size(data.len() as u64);
+	      append_file(&mut tar, &mut header, &path, &data[..], &mut manifest, records, opts)?;
+	    },
+	    None => append_file(&mut tar, &mut header, &path, &mut entry, &mut manifest, records, opts)?,
+	  },
+	  None => {
+	    append_pax(&mut tar, &records)?;
+	    tar.append_data(&mut header, &path, &mut entry)?
+	  },
+	}
+      }
+    },
+    Source::Zip(mut zip) => {
+      for i in 0..zip.len() {
+	let mut file = zip.by_index(i)?;
+	let info = zip_entry_info(&file);
+	if !check(&info.path) {
+	  continue
+	}
+	let mut header = tar::Header::new_gnu();
+	header.set_entry_type(info.kind);
+	header.set_mode(info.mode);
+	header.set_mtime(opts.mtime.apply(info.mtime));
+	header.set_size(if file.is_file() { info.size } else { 0 });
+	tar.get_mut().mark(&info.path)?;
+	if file.is_symlink() {
+	  let mut target = String::new();
+	  file.read_to_string(&mut target)?;
+	  tar.append_link(&mut header, &info.path, target)?;
+	} else if file.is_dir() {
+	  tar.append_data(&mut header, &info.path, io::empty())?;
+	} else {
+	  append_file(&mut tar, &mut header, &info.path, &mut file, &mut manifest, vec![], opts)?;
+	}
+      }
+    },
+    Source::Data(_, Format::Raw) => return Err(unknown_format(src)),
+    Source::Data(mut r, _) => {
+      let mut sink = tar.into_inner()?;
+      io::copy(&mut r, &mut sink)?;
+      sink.finish()?.finish()?;
+      return Ok(skipped)
+    },
+  }
+  tar.get_mut().mark(Path::new(MANIFEST_NAME))?;
+  append_manifest(&mut tar, &manifest, manifest_mtime(opts))?;
+  tar.into_inner()?.finish()?.finish()?;
+  Ok(skipped)
+}
+
+/// Extract the entry at PATH of the seekable archive SRC into DST,
+/// along with everything under it if it is a directory. Only the frames
+/// holding those entries are decompressed. Entries are checked against
+/// OPTS as in `unpack`.
+pub fn extract<P: AsRef<Path>, Q: AsRef<Path>>(src: P, path: &str, dst: Q, opts: &UnpackOptions) -> Result<Unpacked> {
+  let src = src.as_ref();
+  let dst = dst.as_ref();
+  let mut file = crypt::open(src, opts.secret.as_ref())?;
+  let table = SeekTable::read(&mut file)?.ok_or_else(|| Error::NotSeekable(src.to_path_buf()))?;
+  let index = table.index(&mut file)?.ok_or_else(|| Error::NotSeekable(src.to_path_buf()))?;
+  let path = path.trim_end_matches('/');
+  let dir = format!("{}/", path);
+  let matches: Vec<u64> = index.iter()
+    .filter(|(p, _)| p.as_str() != MANIFEST_NAME && (p.as_str() == path || p.starts_with(&dir)))
+    .map(|(_, pos)| *pos)
+    .collect();
+  if matches.is_empty() {
+    return Err(Error::NotFound(path.into()))
+  }
+  fs::create_dir_all(dst)?;
+  let dict = find_dict(src, opts)?;
+  let entry_at = |pos: u64| -> Result<tar::Archive<_>> {
+    Ok(tar::Archive::new(table.reader_at(crypt::open(src, opts.secret.as_ref())?, pos, dict)?))
+  };
+  let mut res = Unpacked::default();
+  for pos in matches {
+    let mut archive = entry_at(pos)?;
+    if let Some(entry) = archive.entries()?.next() {
+      safe::unpack_entry(&mut entry?, dst, opts, &mut res)?;
+    }
+  }
+  safe::set_dir_times(&mut res)?;
+  let manifest = match index.get(MANIFEST_NAME) {
+    Some(pos) => match entry_at(*pos)?.entries()?.next() {
+      Some(entry) => Some(read_manifest(&mut entry?)?),
+      None => None,
+    },
+    None => None,
+  };
+  check_manifest(manifest, dst, &res, |p| p == path || p.starts_with(&dir))?;
+  Ok(res)
+}
+
+/// decompress a zst, gz, xz or bz2 file to DST, unless OPTS keep an
+/// existing file. `-` stands for standard input or output.
+pub fn decompress<P: AsRef<Path>>(src: P, dst: P, opts: &UnpackOptions) -> Result<Unpacked> {
+  let src = src.as_ref();
+  let dst = dst.as_ref();
+  let (mut decoder, format) = formats::decode(src, volume::stream(src)?, opts)?;
+  if matches!(format, Format::Zip | Format::Tar | Format::Raw) {
+    return Err(unknown_format(src))
+  }
+  let mut res = Unpacked::default();
+  if volume::is_stdio(dst) {
+    let mut out = io::stdout().lock();
+    io::copy(&mut decoder, &mut out)?;
+    out.flush()?;
+    res.extracted.push(dst.to_path_buf());
+    return Ok(res)
+  }
+  // data from standard input is as new as it gets
+  let modified = match volume::is_stdio(src) {
+    true => std::time::SystemTime::now(),
+    false => fs::metadata(Input::open(src)?.first())?.modified()?,
+  };
+  let mtime = modified.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  if let Some(reason) = safe::check_dst(dst, mtime, opts)? {
+    res.skipped.push(Skipped { path: dst.to_path_buf(), reason });
+    return Ok(res)
+  }
+  let mut target = io::BufWriter::new(fs::File::create(dst)?);
+  io::copy(&mut decoder, &mut target)?;
+  target.flush()?;
+  res.extracted.push(dst.to_path_buf());
+  Ok(res)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn round_trip() {
+    let tmp = std::env::temp_dir().join("mtz_round_trip");
+    let _ = fs::remove_dir_all(&tmp);
+    let src = tmp.join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("a.txt"), b"hello").unwrap();
+    fs::write(src.join("sub/b.bin"), vec![7u8; 100000]).unwrap();
+    let archive = tmp.join("src.tar.zst");
+    let opts = Options::new().level(Level::Fastest).threads(2).long(true);
+    pack(&src, &archive, &opts).unwrap();
+    let info = info(&archive, &UnpackOptions::new()).unwrap();
+    assert_eq!(info.settings, Some(opts.settings()));
+    // the audio preset leaves FLAC to --audio
+    assert!(!Options::new().preset(Preset::Audio).settings().audio);
+    assert!(info.header.unwrap().checksum);
+
+    let mut paths = vec![];
+    list(&archive, &UnpackOptions::new(), |e| paths.push((e.path.clone(), e.size))).unwrap();
+    assert!(paths.contains(&(std::path::PathBuf::from("src/sub/b.bin"), 100000)));
+    let verified = verify(&archive, &UnpackOptions::new()).unwrap();
+    assert_eq!(verified.bytes, 100005);
+    assert!(verified.manifest && verified.problems.is_empty());
+
+    // flip a byte in the middle of the compressed data
+    let mut bytes = fs::read(&archive).unwrap();
+    let n = bytes.len() / 2;
+    bytes[n] ^= 0xFF;
+    let corrupt = tmp.join("corrupt.tar.zst");
+    fs::write(&corrupt, bytes).unwrap();
+    assert!(verify(&corrupt, &UnpackOptions::new()).is_err());
+
+    // an archive whose manifest doesn't match its contents
+    let bad = tmp.join("bad.tar.zst");
+    let mut tar = tar::Builder::new(Options::new().encoder(fs::File::create(&bad).unwrap()).unwrap());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(2);
+    tar.append_data(&mut header, "x/a.txt", &b"hi"[..]).unwrap();
+    let mut manifest = Manifest::new();
+    manifest.insert(Path::new("x/a.txt"), manifest::hash_reader(&b"ho"[..]).unwrap());
+    manifest.insert(Path::new("x/b.txt"), manifest::hash_reader(&b"hi"[..]).unwrap());
+    append_manifest(&mut tar, &manifest, 0).unwrap();
+    tar.into_inner().unwrap().finish().unwrap();
+    let problems = vec![Problem::Corrupt("x/a.txt".into()), Problem::Missing("x/b.txt".into())];
+    assert_eq!(verify(&bad, &UnpackOptions::new()).unwrap().problems, problems);
+    let uo = UnpackOptions::new();
+    assert!(matches!(unpack(&bad, &tmp.join("out3"), &uo), Err(Error::Manifest(p)) if p == problems));
+    assert_eq!(unpack(&archive, &tmp.join("out"), &uo).unwrap().extracted.len(), 4);
+    // existing files are kept by default
+    assert_eq!(unpack(&archive, &tmp.join("out"), &uo).unwrap().skipped.len(), 2);
+    assert_eq!(fs::read(tmp.join("out/src/a.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(tmp.join("out/src/sub/b.bin")).unwrap(), vec![7u8; 100000]);
+
+    let file = tmp.join("a.txt.zst");
+    compress(&src.join("a.txt"), &file, &Options::new()).unwrap();
+    unpack(&file, &tmp.join("out2"), &uo).unwrap();
+    assert_eq!(unpack(&file, &tmp.join("out2"), &uo).unwrap().skipped[0].reason, safe::Reason::Exists);
+    assert_eq!(fs::read(tmp.join("out2/a.txt")).unwrap(), b"hello");
+
+    assert!(matches!(unpack(&tmp.join("missing.tar.zst"), &tmp, &uo), Err(Error::NotFound(_))));
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+  #[test]
+  fn reproducible() {
+    let tmp = std::env::temp_dir().join("mtz_reproducible");
+    let _ = fs::remove_dir_all(&tmp);
+    let src = tmp.join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("a.txt"), b"hello").unwrap();
+    fs::write(src.join("sub/b.bin"), vec![7u8; 100000]).unwrap();
+    // identical trees with different mtimes
+    let opts = Options::new().reproducible(true).mtime(Mtime::Fixed(1700000000));
+    pack(&src, &tmp.join("r1.tar.zst"), &opts).unwrap();
+    fs::File::options().write(true).open(src.join("a.txt")).unwrap()
+      .set_modified(std::time::UNIX_EPOCH).unwrap();
+    pack(&src, &tmp.join("r2.tar.zst"), &opts).unwrap();
+    assert_eq!(fs::read(tmp.join("r1.tar.zst")).unwrap(), fs::read(tmp.join("r2.tar.zst")).unwrap());
+    let mut mtimes = vec![];
+    list(tmp.join("r1.tar.zst"), &UnpackOptions::new(), |e| mtimes.push(e.mtime)).unwrap();
+    assert!(mtimes.iter().all(|t| *t == 1700000000));
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+  #[test]
+  fn increments() {
+    let tmp = std::env::temp_dir().join("mtz_increments");
+    let _ = fs::remove_dir_all(&tmp);
+    let src = tmp.join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("a.txt"), b"hello").unwrap();
+    fs::write(src.join("sub/b.bin"), vec![7u8; 100000]).unwrap();
+    fs::write(src.join("old.txt"), b"old").unwrap();
+    let full = tmp.join("full.tar.zst");
+    pack(&src, &full, &Options::new()).unwrap();
+    fs::write(src.join("a.txt"), b"changed").unwrap();
+    fs::remove_file(src.join("old.txt")).unwrap();
+    fs::write(src.join("new.txt"), b"new").unwrap();
+    let inc = tmp.join("inc.tar.zst");
+    pack(&src, &inc, &Options::new().base(Some(super::manifest(&full, &UnpackOptions::new()).unwrap()))).unwrap();
+    let m = super::manifest(&inc, &UnpackOptions::new()).unwrap();
+    assert_eq!(m.unchanged.iter().collect::<Vec<_>>(), ["src/sub/b.bin"]);
+    assert_eq!(m.deleted.iter().collect::<Vec<_>>(), ["src/old.txt"]);
+    assert!(verify(&inc, &UnpackOptions::new()).unwrap().problems.is_empty());
+    let uo = UnpackOptions::new();
+    let res = unpack_chain(&full, &[&inc], &tmp.join("out"), &uo).unwrap();
+    assert_eq!(res.deleted, [std::path::PathBuf::from("src/old.txt")]);
+    assert_eq!(fs::read(tmp.join("out/src/a.txt")).unwrap(), b"changed");
+    assert_eq!(fs::read(tmp.join("out/src/sub/b.bin")).unwrap(), vec![7u8; 100000]);
+    assert!(tmp.join("out/src/new.txt").exists() && !tmp.join("out/src/old.txt").exists());
+    // not based on the archive before it
+    assert!(unpack_chain(&inc, &[&inc], &tmp.join("out2"), &uo).is_err());
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+  #[cfg(unix)]
+  #[test]
+  fn chain_deletes_inside() {
+    let tmp = std::env::temp_dir().join("mtz_chain_deletes_inside");
+    let _ = fs::remove_dir_all(&tmp);
+    let src = tmp.join("src");
+    fs::create_dir_all(src.join("out")).unwrap();
+    fs::write(src.join("out/victim"), b"v").unwrap();
+    fs::write(src.join("keep"), b"k").unwrap();
+    let full = tmp.join("full.tar.zst");
+    pack(&src, &full, &Options::new()).unwrap();
+    fs::remove_dir_all(src.join("out")).unwrap();
+    let inc = tmp.join("inc.tar.zst");
+    pack(&src, &inc, &Options::new().base(Some(manifest(&full, &UnpackOptions::new()).unwrap()))).unwrap();
+
+    // an output directory where out leads outside of it
+    let dst = tmp.join("dst");
+    fs::create_dir_all(dst.join("src")).unwrap();
+    fs::create_dir_all(tmp.join("outside")).unwrap();
+    fs::write(tmp.join("outside/victim"), b"v").unwrap();
+    std::os::unix::fs::symlink("../../outside", dst.join("src/out")).unwrap();
+    let res = unpack_chain(&full, &[&inc], &dst, &UnpackOptions::new()).unwrap();
+    assert!(res.deleted.is_empty());
+    assert!(res.skipped.iter().any(|s| s.path == Path::new("src/out/victim") && s.reason == safe::Reason::Traversal));
+    assert!(tmp.join("outside/victim").exists());
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+}
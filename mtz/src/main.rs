@@ -1,153 +1,497 @@
-use std::{fs, io, env, path::Path};
-
-/// Level of compression data should be compressed with.
-#[non_exhaustive]
-#[derive(Clone, Copy, Debug)]
-enum Level {
-  /// Fastest quality of compression, usually produces bigger size.
-  Fastest,
-  /// Best quality of compression, usually produces the smallest size.
-  Best,
-  /// Default quality of compression defined by the selected compression
-  /// algorithm.
-  Default,
-  /// Precise quality based on the underlying compression algorithms'
-  /// qualities. The interpretation of this depends on the algorithm chosen
-  /// and the specific implementation backing it.
-  /// Qualities are implicitly clamped to the algorithm's maximum.
-  Precise(u8),
-}
-
-impl Level {
-  fn into_zstd(self) -> i32 {
-    match self {
-      Self::Fastest => 1,
-      Self::Best => 21,
-      Self::Precise(quality) => quality.min(21) as i32,
-      Self::Default => 0,
-    }
-  }
+//! mtz --- package/unpackage a directory in tar.zst format
+use clap::{Args, Parser, Subcommand};
+use mtz::crypt::Secret;
+use mtz::dict::Dictionary;
+use mtz::volume::is_stdio;
+use mtz::{Level, Mtime, Options, Overwrite, Preset, Symlinks, UnpackOptions};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+#[command(name = "mtz", about = "package/unpackage a directory in tar.zst format")]
+struct Cli {
+  #[command(subcommand)]
+  cmd: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// pack a directory into a tar.zst archive, or compress a file (- for
+  /// standard input)
+  Pack {
+    path: PathBuf,
+    /// output file, - for standard output (default: PATH.tar.zst, or
+    /// PATH.tar.zst.enc when encrypting, and - when PATH is -)
+    #[arg(conflicts_with = "output")]
+    dst: Option<PathBuf>,
+    /// same as DST
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    opts: OptionArgs,
+  },
+  /// unpack an archive (tar.zst, tar.gz, tar.xz, tar.bz2, zip) or a
+  /// compressed file, detected by content
+  Unpack {
+    /// archive or compressed file, - for standard input
+    path: PathBuf,
+    /// output directory, or - to decompress to standard output
+    #[arg(default_value = ".")]
+    dst: PathBuf,
+    /// increments to apply after PATH, in order (repeatable)
+    #[arg(short, long, value_name = "ARCHIVE")]
+    increment: Vec<PathBuf>,
+    #[command(flatten)]
+    opts: UnpackArgs,
+  },
+  /// convert a zip or tar.gz/xz/bz2 archive into a tar.zst archive
+  Repack {
+    path: PathBuf,
+    /// output file (default: PATH with its extensions replaced by
+    /// .tar.zst)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    opts: OptionArgs,
+  },
+  /// extract a single file or directory from a seekable archive
+  Extract {
+    path: PathBuf,
+    /// path of the entry in the archive
+    entry: String,
+    /// output directory
+    #[arg(default_value = ".")]
+    dst: PathBuf,
+    #[command(flatten)]
+    opts: UnpackArgs,
+  },
+  /// show the settings a compressed file was created with
+  Info {
+    path: PathBuf,
+    #[command(flatten)]
+    key: KeyArgs,
+  },
+  /// list the contents of an archive
+  List {
+    path: PathBuf,
+    /// dictionary to decompress with (repeatable)
+    #[arg(long, value_name = "FILE")]
+    dict: Vec<PathBuf>,
+    #[command(flatten)]
+    key: KeyArgs,
+  },
+  /// decode a whole archive, checking checksums and every entry
+  Verify {
+    path: PathBuf,
+    /// dictionary to decompress with (repeatable)
+    #[arg(long, value_name = "FILE")]
+    dict: Vec<PathBuf>,
+    #[command(flatten)]
+    key: KeyArgs,
+  },
+  /// write a new random key file for --key-file
+  Keygen {
+    /// output file
+    #[arg(default_value = "mtz.key")]
+    output: PathBuf,
+  },
+  /// manage zstd dictionaries
+  Dict {
+    #[command(subcommand)]
+    cmd: DictCommand,
+  },
 }
 
-impl From<u8> for Level {
-  fn from(n: u8) -> Self {
-    match n {
-      0 => Self::Default,
-      1 => Self::Fastest,
-      2 => Self::Best,
-      n => Self::Precise(n),
+#[derive(Subcommand)]
+enum DictCommand {
+  /// train a dictionary on every file under DIR
+  Train {
+    dir: PathBuf,
+    /// output file
+    #[arg(short, long, default_value = "mtz.dict")]
+    output: PathBuf,
+    /// maximum dictionary size (K, M suffixes)
+    #[arg(long, default_value = "110K", value_parser = mtz::volume::parse_size)]
+    max_size: u64,
+  },
+}
+
+#[derive(Args)]
+struct OptionArgs {
+  /// fastest, best, default or 1-21
+  #[arg(short, long)]
+  level: Option<Level>,
+  /// number of compression threads (0 = one per CPU)
+  #[arg(short = 'T', long)]
+  threads: Option<u32>,
+  /// long-distance matching, for big media archives
+  #[arg(long)]
+  long: bool,
+  /// store WAV and AIFF samples as FLAC, restored byte for byte
+  #[arg(long)]
+  audio: bool,
+  /// preset options, applied before the other flags (audio)
+  #[arg(long)]
+  preset: Option<Preset>,
+  /// one frame per entry plus a seek table, for use with extract
+  #[arg(long)]
+  seekable: bool,
+  /// leave out files matching PATTERN (gitignore syntax, repeatable)
+  #[arg(short = 'x', long, value_name = "PATTERN")]
+  exclude: Vec<String>,
+  /// keep common junk such as .DS_Store, ._* and target/
+  #[arg(long)]
+  no_default_excludes: bool,
+  /// normalized owners, permissions and manifest time, so identical
+  /// trees give identical archives. mtimes are clamped to
+  /// SOURCE_DATE_EPOCH if it is set
+  #[arg(long)]
+  reproducible: bool,
+  /// store SECS (since the epoch) as the mtime of every entry
+  #[arg(long, value_name = "SECS", conflicts_with = "clamp_mtime")]
+  mtime: Option<u64>,
+  /// write volumes of at most SIZE bytes (K, M, G suffixes), as
+  /// OUTPUT.001, OUTPUT.002, ...
+  #[arg(long, value_name = "SIZE", value_parser = mtz::volume::parse_size)]
+  split: Option<u64>,
+  /// store mtimes no later than SECS (since the epoch)
+  #[arg(long, value_name = "SECS")]
+  clamp_mtime: Option<u64>,
+  /// store only files new or changed since BASE (an archive or a saved
+  /// manifest), and the files deleted since
+  #[arg(long, value_name = "BASE")]
+  since: Option<PathBuf>,
+  /// compress with a dictionary made by `mtz dict train`
+  #[arg(long, value_name = "FILE")]
+  dict: Option<PathBuf>,
+  /// store extended attributes (PAX SCHILY.xattr records)
+  #[arg(long)]
+  xattrs: bool,
+  /// store mtimes to the nanosecond (PAX mtime records)
+  #[arg(long)]
+  precise_mtime: bool,
+  /// --xattrs and --precise-mtime
+  #[arg(short, long)]
+  preserve: bool,
+  #[command(flatten)]
+  key: KeyArgs,
+}
+
+impl OptionArgs {
+  fn options(&self) -> mtz::Result<Options> {
+    let mut opts = Options::new();
+    let dicts = load_dicts(self.dict.iter())?;
+    let secret = self.key.secret()?;
+    if let Some(base) = &self.since {
+      let read = dicts.iter().fold(UnpackOptions::new(), |o, d| o.dict(d.clone())).secret(secret.clone());
+      opts = opts.base(Some(mtz::manifest(base, &read)?));
+    }
+    opts = opts.encrypt(secret);
+    opts = opts.dict(dicts.into_iter().next());
+    if let Some(preset) = self.preset {
+      opts = opts.preset(preset);
+    }
+    if let Some(level) = self.level {
+      opts = opts.level(level);
+    }
+    if let Some(threads) = self.threads {
+      opts = opts.threads(match threads {
+	0 => std::thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(1),
+	n => n,
+      });
+    }
+    if self.long {
+      opts = opts.long(true);
+    }
+    if self.seekable {
+      opts = opts.seekable(true);
+    }
+    if self.audio {
+      opts = opts.audio(true);
+    }
+    let epoch = std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|s| s.trim().parse().ok());
+    match (self.mtime, self.clamp_mtime, epoch) {
+      (Some(t), _, _) => opts = opts.mtime(Mtime::Fixed(t)),
+      (_, Some(t), _) => opts = opts.mtime(Mtime::Clamp(t)),
+      (_, _, Some(t)) if self.reproducible => opts = opts.mtime(Mtime::Clamp(t)),
+      _ => (),
     }
+    if self.reproducible {
+      opts = opts.reproducible(true);
+    }
+    if self.xattrs || self.preserve {
+      opts = opts.xattrs(true);
+    }
+    if self.precise_mtime || self.preserve {
+      opts = opts.precise_mtime(true);
+    }
+    opts = opts.split(self.split);
+    for pattern in &self.exclude {
+      opts = opts.exclude(pattern);
+    }
+    Ok(opts.default_excludes(!self.no_default_excludes))
   }
 }
 
-fn is_tar<P: AsRef<Path>>(src: P) -> bool {
-  let p = src.as_ref();
-  if p
-    .file_name()
-    .map(|p| p.to_str().unwrap())
-    .unwrap()
-    .contains(".tar")
-  {
-    true
-  } else {
-    false
-  }
+#[derive(Args)]
+struct UnpackArgs {
+  /// what to do with links: skip, inside (the output directory) or allow
+  #[arg(long, default_value = "inside")]
+  symlinks: Symlinks,
+  /// replace existing files: never, newer or always
+  #[arg(long, default_value = "never")]
+  overwrite: Overwrite,
+  /// dictionary to decompress with (repeatable)
+  #[arg(long, value_name = "FILE")]
+  dict: Vec<PathBuf>,
+  /// restore stored extended attributes
+  #[arg(long)]
+  xattrs: bool,
+  /// with --xattrs, restore those outside the user namespace too
+  #[arg(long)]
+  all_xattrs: bool,
+  /// restore setuid, setgid and sticky bits too
+  #[arg(long)]
+  same_permissions: bool,
+  /// restore stored mtimes to the nanosecond
+  #[arg(long)]
+  precise_mtime: bool,
+  /// --xattrs, --same-permissions and --precise-mtime
+  #[arg(short, long)]
+  preserve: bool,
+  #[command(flatten)]
+  key: KeyArgs,
 }
 
-/// Pack a SRC directory, and return a compressed archive at DST.
-fn pack<P: AsRef<Path>>(src: P, dst: P, level: Option<Level>) {
-  let src = src.as_ref();
-  let dst = dst.as_ref();
-  if !src.is_file() {
-    let mut tar = tar::Builder::new(Vec::new());
-    let parent = src.parent().unwrap();
-    let art = src.strip_prefix(parent).unwrap();
-    tar.append_dir_all(art, src).unwrap();
-    let tar = tar.into_inner().unwrap();
-    let file = fs::File::create(dst).expect("failed to create output path");
-    zstd::stream::copy_encode(
-      &tar[..],
-      file,
-      level.unwrap_or(Level::Default).into_zstd(),
-    )
-    .unwrap();
-  } else {
-    compress(src, dst, level).unwrap()
+impl UnpackArgs {
+  fn options(&self) -> mtz::Result<UnpackOptions> {
+    read_options(&self.dict, &self.key).map(|o| o.symlinks(self.symlinks).overwrite(self.overwrite)
+      .xattrs(self.xattrs || self.preserve)
+      .all_xattrs(self.all_xattrs)
+      .permissions(self.same_permissions || self.preserve)
+      .precise_mtime(self.precise_mtime || self.preserve))
   }
 }
 
-/// unpack a tar.zst compressed archive or zst file
-fn unpack<P: AsRef<Path>>(src: P, dst: P) {
-  let src = src.as_ref();
-  let input = fs::File::open(src).expect("failed to open input");
-  let mut buff = Vec::new();
-  zstd::stream::copy_decode(input, &mut buff).unwrap();
-  if is_tar(src) {
-    tar::Archive::new(&buff[..]).unpack(dst).unwrap();
-  } else {
-    decompress(src, dst.as_ref()).unwrap()
+#[derive(Args)]
+struct KeyArgs {
+  /// encrypt or decrypt with a key file made by `mtz keygen`
+  #[arg(long, value_name = "FILE", conflicts_with = "passphrase_file")]
+  key_file: Option<PathBuf>,
+  /// encrypt or decrypt with the passphrase on the first line of FILE.
+  /// The MTZ_PASSPHRASE environment variable is used otherwise, if set
+  #[arg(long, value_name = "FILE")]
+  passphrase_file: Option<PathBuf>,
+}
+
+impl KeyArgs {
+  /// Whether a key or passphrase is given.
+  fn is_set(&self) -> bool {
+    self.key_file.is_some() || self.passphrase_file.is_some() || std::env::var_os("MTZ_PASSPHRASE").is_some()
+  }
+  fn secret(&self) -> mtz::Result<Option<Secret>> {
+    if let Some(path) = &self.key_file {
+      return Secret::key_file(path).map(Some)
+    }
+    let passphrase = match &self.passphrase_file {
+      Some(path) => std::fs::read_to_string(path)?.lines().next().unwrap_or("").to_string(),
+      None => match std::env::var("MTZ_PASSPHRASE") {
+	Ok(p) => p,
+	Err(_) => return Ok(None),
+      },
+    };
+    if passphrase.is_empty() {
+      return Err(mtz::Error::Secret("empty passphrase"))
+    }
+    Ok(Some(Secret::passphrase(&passphrase)))
   }
 }
 
-/// unpack a tar.zst compressed archive, removing the source file before
-/// returning
-fn unpack_replace<P: AsRef<Path>>(src: P, dst: P) {
-  unpack(&src, &dst);
-  fs::remove_file(src).expect("could not remove source package");
+fn load_dicts<'a, I: Iterator<Item = &'a PathBuf>>(paths: I) -> mtz::Result<Vec<Dictionary>> {
+  paths.map(Dictionary::open).collect()
 }
 
-/// compress a file with zstd
-fn compress<P: AsRef<Path>>(
-  src: P,
-  dst: P,
-  level: Option<Level>,
-) -> io::Result<()> {
-  let mut file = fs::File::open(&src)?;
-  let mut encoder = {
-    let target = fs::File::create(dst.as_ref())?;
-    zstd::Encoder::new(target, level.unwrap_or(Level::Default).into_zstd())?
-  };
-  io::copy(&mut file, &mut encoder)?;
-  encoder.finish()?;
+/// Options to read inputs with DICTS and the secret of KEY.
+fn read_options(dicts: &[PathBuf], key: &KeyArgs) -> mtz::Result<UnpackOptions> {
+  let opts = UnpackOptions::new().secret(key.secret()?);
+  Ok(load_dicts(dicts.iter())?.into_iter().fold(opts, |o, d| o.dict(d)))
+}
+
+/// Write a new key file at OUTPUT, readable only by its owner.
+fn keygen(output: PathBuf) -> mtz::Result<()> {
+  use std::io::Write;
+  let mut file = std::fs::OpenOptions::new();
+  file.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+  file.open(&output)?.write_all(&mtz::crypt::generate_key()?)?;
+  println!("{}: new key file, keep a copy in a safe place", output.display());
   Ok(())
 }
 
-/// decompress a zst file into the current directory
-fn decompress<P: AsRef<Path>>(src: P, dst: P) -> io::Result<()> {
-  let mut decoder = {
-    let file = fs::File::open(&src)?;
-    zstd::Decoder::new(file)?
-  };
-  let mut target = fs::File::create(dst.as_ref().to_str().unwrap())?;
-  io::copy(&mut decoder, &mut target)?;
-  decoder.finish();
+fn train(dir: PathBuf, output: PathBuf, max_size: u64) -> mtz::Result<()> {
+  let (dict, samples) = mtz::dict::train(&dir, max_size as usize)?;
+  std::fs::write(&output, dict.as_bytes())?;
+  println!("{}: dictionary {}, {} bytes from {} samples", output.display(), dict.id(), dict.as_bytes().len(), samples);
   Ok(())
 }
 
-fn main() {
-  let args: Vec<String> = env::args().collect();
-  if args.len() == 1 {
-    println!("mtz [pack|unpack] PATH");
-  } else {
-    let mut args = args.iter().skip(1);
-    match args.next().unwrap().as_str() {
-      "pack" => {
-	let path = args.next().ok_or("expected a path").unwrap();
-	let path = Path::new(&path);
-	pack(&path, &path.with_extension("tar.zst").as_path(), None);
-      },
-      "unpack" => {
-	let path = args.next().ok_or("expected a path").unwrap();
-	let path = Path::new(&path);
-	if !&path.exists() {
-	  println!("file does not exist");
-	} else {
-	  unpack(&path, &Path::new("."));
-	}
-      },
-      _ => {
-	println!("mtz [pack|unpack] PATH");
+/// Report skipped entries on stderr, and the rest on stdout unless
+/// PIPED, when stdout holds the output.
+fn report(res: mtz::Unpacked, piped: bool) {
+  for s in &res.skipped {
+    eprintln!("mtz: {}", s);
+  }
+  if piped {
+    return
+  }
+  for d in &res.deleted {
+    println!("deleted {}", d.display());
+  }
+  println!("extracted {} entries, skipped {}", res.extracted.len(), res.skipped.len());
+}
+
+fn info(path: PathBuf, key: KeyArgs) -> mtz::Result<()> {
+  let info = mtz::info(&path, &read_options(&[], &key)?)?;
+  println!("file: {}", path.display());
+  println!("size: {}", info.size);
+  println!("format: {}", info.format.extension().unwrap_or("unknown"));
+  println!("encrypted: {}", info.encrypted);
+  match info.settings {
+    Some(s) => {
+      println!("level: {}", s.level);
+      println!("threads: {}", s.threads);
+      println!("long: {}", s.long);
+      println!("reproducible: {}", s.reproducible);
+      println!("audio: {}", s.audio);
+      println!("xattrs: {}", s.xattrs);
+      println!("precise_mtime: {}", s.precise_mtime);
+      if let Some(log) = s.window_log {
+	println!("window_log: {}", log);
+      }
+      if let Some(n) = s.split {
+	println!("split: {}", n);
       }
+    },
+    None => println!("settings: not recorded"),
+  }
+  if let Some(h) = info.header {
+    if let Some(w) = h.window_size {
+      println!("window_size: {}", w);
+    }
+    if let Some(id) = h.dict_id.filter(|id| *id != 0) {
+      println!("dict_id: {}", id);
     }
+    if let Some(n) = h.content_size {
+      println!("content_size: {}", n);
+    }
+    println!("checksum: {}", h.checksum);
+  }
+  if let Some(n) = info.frames {
+    println!("seekable: {} frames", n);
+  }
+  Ok(())
+}
+
+/// Default output of repack: SRC without its archive extensions, as
+/// tar.zst, or tar.zst.enc if ENCRYPTED.
+fn repack_output(src: &Path, encrypted: bool) -> PathBuf {
+  let mut out = src.to_path_buf();
+  while let Some(ext) = out.extension().and_then(|e| e.to_str()) {
+    if !["enc", "zst", "gz", "xz", "bz2", "zip", "tar", "tgz", "txz", "tbz", "tbz2"].contains(&ext.to_lowercase().as_str()) {
+      break
+    }
+    out.set_extension("");
+  }
+  out.with_extension(if encrypted { "tar.zst.enc" } else { "tar.zst" })
+}
+
+/// Format a unix timestamp as UTC date and time.
+fn format_time(secs: u64) -> String {
+  // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+  let days = (secs / 86400) as i64 + 719468;
+  let era = days / 146097;
+  let doe = days - era * 146097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let d = doy - (153 * mp + 2) / 5 + 1;
+  let m = if mp < 10 { mp + 3 } else { mp - 9 };
+  let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+  format!("{:04}-{:02}-{:02} {:02}:{:02}", y, m, d, secs % 86400 / 3600, secs % 3600 / 60)
+}
+
+fn format_mode(kind: tar::EntryType, mode: u32) -> String {
+  let mut s = String::from(match kind {
+    tar::EntryType::Directory => 'd',
+    tar::EntryType::Symlink => 'l',
+    tar::EntryType::Link => 'h',
+    _ => '-',
+  });
+  for i in (0..9).rev() {
+    s.push(if mode & (1 << i) == 0 { '-' } else { ['x', 'w', 'r'][i % 3] });
+  }
+  // setuid, setgid and sticky bits over the execute bits, as ls shows them
+  let mut s: Vec<char> = s.chars().collect();
+  for (bit, at, c) in [(0o4000, 3, 's'), (0o2000, 6, 's'), (0o1000, 9, 't')] {
+    if mode & bit != 0 {
+      s[at] = if s[at] == 'x' { c } else { c.to_ascii_uppercase() };
+    }
+  }
+  s.into_iter().collect()
+}
+
+fn main() {
+  let cli = Cli::parse();
+  let res = match cli.cmd {
+    Command::Pack { path, dst, output, opts } => opts.options().and_then(|o| {
+      let enc = if opts.key.is_set() { ".enc" } else { "" };
+      let dst = dst.or(output).unwrap_or_else(|| if is_stdio(&path) {
+	path.clone()
+      } else if path.is_file() {
+	PathBuf::from(format!("{}.zst{}", path.display(), enc))
+      } else {
+	PathBuf::from(format!("{}{}", path.with_extension("tar.zst").display(), enc))
+      });
+      if is_stdio(&dst) && std::io::stdout().is_terminal() {
+	return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "refusing to write compressed data to a terminal").into())
+      }
+      mtz::pack(path, dst, &o)
+    }),
+    Command::Unpack { path, dst, increment, opts } => {
+      let piped = is_stdio(&dst);
+      opts.options().and_then(|opts| match increment.is_empty() {
+	true => mtz::unpack(path, dst, &opts),
+	false => mtz::unpack_chain(path, &increment, dst, &opts),
+      }).map(|res| report(res, piped))
+    },
+    Command::Repack { path, output, opts } => opts.options().and_then(|o| {
+      let dst = output.unwrap_or_else(|| repack_output(&path, opts.key.is_set()));
+      for s in mtz::repack(path, dst, &o)? {
+	eprintln!("mtz: {}", s);
+      }
+      Ok(())
+    }),
+    Command::Extract { path, entry, dst, opts } => opts.options()
+      .and_then(|opts| mtz::extract(path, &entry, dst, &opts))
+      .map(|res| report(res, false)),
+    Command::Info { path, key } => info(path, key),
+    Command::Keygen { output } => keygen(output),
+    Command::Dict { cmd: DictCommand::Train { dir, output, max_size } } => train(dir, output, max_size),
+    Command::List { path, dict, key } => read_options(&dict, &key).and_then(|opts| mtz::list(path, &opts, |e| {
+      println!("{} {:>12} {} {}", format_mode(e.kind, e.mode), e.size, format_time(e.mtime), e.path.display());
+    })),
+    Command::Verify { path, dict, key } => read_options(&dict, &key).and_then(|opts| mtz::verify(&path, &opts)).and_then(|v| {
+      if !v.problems.is_empty() {
+	return Err(mtz::Error::Manifest(v.problems))
+      }
+      let manifest = if v.manifest { ", manifest ok" } else { "" };
+      println!("{}: ok ({} entries, {} bytes{})", path.display(), v.entries, v.bytes, manifest);
+      Ok(())
+    }),
+  };
+  if let Err(e) = res {
+    eprintln!("mtz: {}", e);
+    std::process::exit(1);
   }
 }
@@ -0,0 +1,147 @@
+//! manifest --- per-file hashes stored inside archives
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read};
+use std::path::Path;
+use std::{fmt, fs};
+
+/// Name of the manifest entry, stored at the root of the archive.
+pub const MANIFEST_NAME: &str = ".mtz-manifest.json";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileHash {
+  pub size: u64,
+  pub blake3: String,
+}
+
+/// Size and BLAKE3 hash of every regular file in an archive, keyed by
+/// its path in the archive.
+///
+/// The manifest of an incremental archive lists every file of the
+/// packed tree, but only stores those which aren't UNCHANGED since the
+/// archive with state BASE.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+  pub version: u32,
+  pub files: BTreeMap<String, FileHash>,
+  /// State of the manifest this is an increment of.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub base: Option<String>,
+  /// Files which are the same as in the base, and not stored.
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub unchanged: BTreeSet<String>,
+  /// Files of the base which have been deleted.
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  pub deleted: BTreeSet<String>,
+}
+
+/// A file which doesn't match the manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+  Missing(String),
+  Corrupt(String),
+}
+
+impl fmt::Display for Problem {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Problem::Missing(p) => write!(f, "missing: {}", p),
+      Problem::Corrupt(p) => write!(f, "corrupt: {}", p),
+    }
+  }
+}
+
+impl Manifest {
+  pub fn new() -> Self {
+    Manifest { version: 1, ..Default::default() }
+  }
+
+  /// Hash of the paths, sizes and hashes of all files, which identifies
+  /// the state of the packed tree.
+  pub fn state(&self) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for (path, hash) in &self.files {
+      hasher.update(format!("{}\0{}\0{}\n", path, hash.size, hash.blake3).as_bytes());
+    }
+    hasher.finalize().to_hex().to_string()
+  }
+
+  /// Turn this into an increment of BASE, listing the files deleted
+  /// since.
+  pub fn set_base(&mut self, base: &Manifest) {
+    self.base = Some(base.state());
+    self.deleted = base.files.keys().filter(|p| !self.files.contains_key(*p)).cloned().collect();
+  }
+
+  pub fn insert(&mut self, path: &Path, hash: FileHash) {
+    self.files.insert(path.to_string_lossy().to_string(), hash);
+  }
+
+  pub fn to_vec(&self) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec_pretty(self).map_err(io::Error::from)?)
+  }
+
+  pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+    Ok(serde_json::from_slice(bytes).map_err(io::Error::from)?)
+  }
+
+  /// Compare against HASHES computed from the contents of an archive.
+  /// Unchanged files of an increment aren't expected in HASHES.
+  pub fn compare(&self, hashes: &BTreeMap<String, FileHash>) -> Vec<Problem> {
+    self.files.iter()
+      .filter(|(path, _)| !self.unchanged.contains(*path))
+      .filter_map(|(path, hash)| match hashes.get(path) {
+	None => Some(Problem::Missing(path.clone())),
+	Some(h) if h != hash => Some(Problem::Corrupt(path.clone())),
+	_ => None,
+      })
+      .collect()
+  }
+
+  /// Compare against the files extracted into directory DST.
+  pub fn check<P: AsRef<Path>>(&self, dst: P) -> Vec<Problem> {
+    let dst = dst.as_ref();
+    self.files.iter()
+      .filter_map(|(path, hash)| match fs::File::open(dst.join(path)) {
+	Err(_) => Some(Problem::Missing(path.clone())),
+	Ok(file) => match hash_reader(file) {
+	  Ok(h) if h == *hash => None,
+	  _ => Some(Problem::Corrupt(path.clone())),
+	},
+      })
+      .collect()
+  }
+}
+
+/// A reader which hashes everything read through it.
+pub struct HashReader<R> {
+  inner: R,
+  hasher: blake3::Hasher,
+  size: u64,
+}
+
+impl<R: Read> HashReader<R> {
+  pub fn new(inner: R) -> Self {
+    HashReader { inner, hasher: blake3::Hasher::new(), size: 0 }
+  }
+  pub fn finish(&self) -> FileHash {
+    FileHash { size: self.size, blake3: self.hasher.finalize().to_hex().to_string() }
+  }
+}
+
+impl<R: Read> Read for HashReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    self.hasher.update(&buf[..n]);
+    self.size += n as u64;
+    Ok(n)
+  }
+}
+
+/// Hash everything in R.
+pub fn hash_reader<R: Read>(r: R) -> io::Result<FileHash> {
+  let mut r = HashReader::new(r);
+  io::copy(&mut r, &mut io::sink())?;
+  Ok(r.finish())
+}
@@ -0,0 +1,347 @@
+//! meta --- extended attributes, permissions and precise mtimes
+//!
+//! Metadata tar headers can't hold is stored in a PAX header before the
+//! entry, with the keys GNU tar and bsdtar use:
+//!
+//! - `SCHILY.xattr.NAME` holds the raw value of the extended attribute
+//!   NAME, such as the Finder tags of macOS files.
+//! - `LIBARCHIVE.xattr.NAME` holds it in base64 instead, with NAME
+//!   URL-encoded, for values with newlines, which tar-rs can't read raw.
+//! - `mtime` holds the modification time in seconds, with up to nine
+//!   decimals.
+//!
+//! Permissions, including the setuid, setgid and sticky bits, are kept
+//! in the mode field of the header itself.
+use crate::{Options, Result};
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use filetime::FileTime;
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::path::Path;
+use std::fs;
+
+/// PAX key prefix of extended attributes.
+pub const XATTR_PREFIX: &str = "SCHILY.xattr.";
+/// PAX key prefix of encoded extended attributes.
+pub const ENCODED_XATTR_PREFIX: &str = "LIBARCHIVE.xattr.";
+/// PAX key of precise modification times.
+pub const MTIME_KEY: &str = "mtime";
+
+/// PAX records, as keys and values.
+pub type Records = Vec<(String, Vec<u8>)>;
+
+/// Metadata of an entry read from its PAX records.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Extended {
+  pub xattrs: Vec<(OsString, Vec<u8>)>,
+  pub mtime: Option<FileTime>,
+}
+
+/// Format MTIME as decimal seconds, without trailing zeros.
+fn format_mtime(mtime: FileTime) -> String {
+  match mtime.nanoseconds() {
+    0 => mtime.unix_seconds().to_string(),
+    ns => format!("{}.{:09}", mtime.unix_seconds(), ns).trim_end_matches('0').to_string(),
+  }
+}
+
+/// Parse decimal seconds, as written by `format_mtime`.
+fn parse_mtime(s: &str) -> Option<FileTime> {
+  let (secs, frac) = s.split_once('.').unwrap_or((s, ""));
+  if frac.len() > 9 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+    return None
+  }
+  let ns = format!("{:0<9}", frac).parse().ok()?;
+  Some(FileTime::from_unix_time(secs.parse().ok()?, ns))
+}
+
+/// PAX records for the entry of the file at PATH with metadata META:
+/// its extended attributes and precise mtime, as OPTS ask for.
+pub(crate) fn records(path: &Path, meta: &fs::Metadata, opts: &Options) -> Result<Records> {
+  let mut records = vec![];
+  if opts.precise_mtime {
+    let mtime = FileTime::from_last_modification_time(meta);
+    let secs = mtime.unix_seconds().max(0) as u64;
+    // times changed by OPTS are whole seconds
+    let value = match opts.mtime.apply(secs) {
+      t if t == secs => format_mtime(mtime),
+      t => t.to_string(),
+    };
+    records.push((MTIME_KEY.to_string(), value.into_bytes()));
+  }
+  if opts.xattrs && !meta.file_type().is_symlink() {
+    records.extend(xattrs(path)?);
+  }
+  Ok(records)
+}
+
+/// The extended attributes of PATH, in name order. Filesystems without
+/// extended attributes have none.
+#[cfg(unix)]
+fn xattrs(path: &Path) -> io::Result<Records> {
+  let mut names: Vec<OsString> = match xattr::list(path) {
+    Ok(names) => names.collect(),
+    Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(vec![]),
+    Err(e) => return Err(e),
+  };
+  names.sort();
+  let mut records = vec![];
+  for name in names {
+    // PAX keys are UTF-8
+    let Some(key) = name.to_str() else { continue };
+    match xattr::get(path, &name)? {
+      Some(value) if value.contains(&b'\n') || key.contains('=') => {
+	records.push((format!("{}{}", ENCODED_XATTR_PREFIX, url_encode(key)), base64_encode(&value).into_bytes()))
+      },
+      Some(value) => records.push((format!("{}{}", XATTR_PREFIX, key), value)),
+      None => (),
+    }
+  }
+  Ok(records)
+}
+
+#[cfg(not(unix))]
+fn xattrs(_: &Path) -> io::Result<Records> {
+  Ok(vec![])
+}
+
+/// The records of ENTRY kept by `records`, to copy into another
+/// archive. The mtime is left out unless KEEP_MTIME.
+pub(crate) fn copy_records<R: Read>(entry: &mut tar::Entry<R>, keep_mtime: bool) -> Result<Records> {
+  let mut records = vec![];
+  if let Some(pax) = entry.pax_extensions()? {
+    // raw values with newlines, as GNU tar writes them, end the
+    // records tar-rs can read
+    for ext in pax.map_while(|ext| ext.ok()) {
+      let Ok(key) = ext.key() else { continue };
+      if key.starts_with(XATTR_PREFIX) || key.starts_with(ENCODED_XATTR_PREFIX) || (keep_mtime && key == MTIME_KEY) {
+	records.push((key.to_string(), ext.value_bytes().to_vec()));
+      }
+    }
+  }
+  Ok(records)
+}
+
+/// Read the extended attributes and precise mtime of ENTRY, as far as
+/// its records can be read.
+pub(crate) fn read<R: Read>(entry: &mut tar::Entry<R>) -> Result<Extended> {
+  let mut res = Extended::default();
+  if let Some(pax) = entry.pax_extensions()? {
+    for ext in pax.map_while(|ext| ext.ok()) {
+      let Ok(key) = ext.key() else { continue };
+      if let Some(name) = key.strip_prefix(XATTR_PREFIX) {
+	res.xattrs.push((name.into(), ext.value_bytes().to_vec()));
+      } else if let Some(name) = key.strip_prefix(ENCODED_XATTR_PREFIX) {
+	let value = ext.value().ok().and_then(base64_decode);
+	if let (Some(name), Some(value)) = (url_decode(name), value) {
+	  res.xattrs.push((name, value));
+	}
+      } else if key == MTIME_KEY {
+	res.mtime = ext.value().ok().and_then(parse_mtime);
+      }
+    }
+  }
+  Ok(res)
+}
+
+/// Base64 with padding, read with or without it.
+const BASE64: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, GeneralPurposeConfig::new()
+  .with_decode_padding_mode(DecodePaddingMode::Indifferent));
+/// Bytes escaped in encoded names: `%`, `=` and anything but printable
+/// ASCII.
+const NAME_ESCAPES: &AsciiSet = &CONTROLS.add(b' ').add(b'%').add(b'=');
+
+fn base64_encode(data: &[u8]) -> String {
+  BASE64.encode(data)
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+  BASE64.decode(s).ok()
+}
+
+fn url_encode(name: &str) -> String {
+  utf8_percent_encode(name, NAME_ESCAPES).to_string()
+}
+
+/// Decode NAME from `url_encode`, if it is UTF-8.
+fn url_decode(name: &str) -> Option<OsString> {
+  percent_decode_str(name).decode_utf8().ok().map(|s| OsString::from(s.as_ref()))
+}
+
+/// Whether the extended attribute NAME is restored without ALL. On
+/// Linux only the user namespace is, as with GNU tar, since the others
+/// need privileges or mean something to the system.
+#[cfg(unix)]
+fn restored(name: &std::ffi::OsStr, all: bool) -> bool {
+  use std::os::unix::ffi::OsStrExt;
+  all || !cfg!(target_os = "linux") || name.as_bytes().starts_with(b"user.")
+}
+
+/// Set the extended attributes XATTRS on PATH, all of them if ALL.
+/// Returns the names the filesystem or our privileges didn't allow.
+#[cfg(unix)]
+pub(crate) fn set_xattrs(path: &Path, xattrs: &[(OsString, Vec<u8>)], all: bool) -> io::Result<Vec<OsString>> {
+  let mut rejected = vec![];
+  for (name, value) in xattrs.iter().filter(|(name, _)| restored(name, all)) {
+    match xattr::set(path, name, value) {
+      Ok(()) => (),
+      Err(e) if e.raw_os_error().is_some_and(|n| [libc::ENOTSUP, libc::EOPNOTSUPP, libc::EPERM].contains(&n)) => {
+	rejected.push(name.clone())
+      },
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(rejected)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_xattrs(_: &Path, _: &[(OsString, Vec<u8>)], _: bool) -> io::Result<Vec<OsString>> {
+  Ok(vec![])
+}
+
+/// A PAX extended header entry with RECORDS, for the entry after it.
+pub(crate) fn pax_header(records: &[(String, Vec<u8>)]) -> (tar::Header, Vec<u8>) {
+  let mut data = vec![];
+  for (k, v) in records {
+    // the record length counts its own digits
+    let rest = k.len() + v.len() + 3;
+    let mut len = rest + rest.to_string().len();
+    len += (len.to_string().len() > rest.to_string().len()) as usize;
+    data.extend(format!("{} {}=", len, k).bytes());
+    data.extend(v);
+    data.push(b'\n');
+  }
+  let mut header = tar::Header::new_ustar();
+  header.set_entry_type(tar::EntryType::XHeader);
+  header.set_path("PaxHeader").unwrap();
+  header.set_size(data.len() as u64);
+  header.set_mode(0o644);
+  header.set_cksum();
+  (header, data)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn records_round_trip() {
+    assert_eq!(format_mtime(FileTime::from_unix_time(12, 500_000_000)), "12.5");
+    assert_eq!(format_mtime(FileTime::from_unix_time(12, 0)), "12");
+    assert_eq!(parse_mtime("12.000000001"), Some(FileTime::from_unix_time(12, 1)));
+    assert_eq!(parse_mtime("12"), Some(FileTime::from_unix_time(12, 0)));
+    assert_eq!(parse_mtime("12.x"), None);
+
+    assert_eq!(base64_encode(b"red\n\0blue"), "cmVkCgBibHVl");
+    assert_eq!(base64_encode(b"ab"), "YWI=");
+    assert_eq!(base64_decode("YWI").unwrap(), b"ab");
+    assert_eq!(url_encode("user.a=b c"), "user.a%3Db%20c");
+    // lengths crossing a digit boundary
+    let value = vec![b'v'; 90];
+    let records = vec![
+      (format!("{}{}", ENCODED_XATTR_PREFIX, url_encode("user.a=b")), base64_encode(b"red\n\0blue").into_bytes()),
+      (MTIME_KEY.to_string(), b"1700000000.123456789".to_vec()),
+      ("SCHILY.xattr.user.x".to_string(), value.clone()),
+    ];
+    let (pax, data) = pax_header(&records);
+    let mut tar = tar::Builder::new(vec![]);
+    tar.append(&pax, &data[..]).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(0);
+    header.set_cksum();
+    tar.append_data(&mut header, "a", io::empty()).unwrap();
+    let tar = tar.into_inner().unwrap();
+    let mut archive = tar::Archive::new(&tar[..]);
+    let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+    let ext = read(&mut entry).unwrap();
+    assert_eq!(ext.mtime, Some(FileTime::from_unix_time(1700000000, 123456789)));
+    assert_eq!(ext.xattrs, [("user.a=b".into(), b"red\n\0blue".to_vec()), ("user.x".into(), value)]);
+    assert_eq!(copy_records(&mut entry, false).unwrap().len(), 2);
+  }
+  #[test]
+  fn encodings() {
+    for data in [&b""[..], b"a", b"ab", b"abc", b"\0\xFF\n"] {
+      assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data);
+    }
+    assert_eq!(base64_encode(b"a"), "YQ==");
+    assert_eq!(base64_decode("YQ").unwrap(), b"a");
+    for bad in ["Y", "YQ=!", "Y Q=", "YQ==YQ=="] {
+      assert_eq!(base64_decode(bad), None);
+    }
+    for name in ["user.plain", "user.a=b%20c", "user.tab\tand space", "user.caf\u{e9}"] {
+      assert_eq!(url_decode(&url_encode(name)).unwrap(), name);
+    }
+    assert_eq!(url_encode("user.caf\u{e9}"), "user.caf%C3%A9");
+    // names which aren't UTF-8 once decoded
+    assert_eq!(url_decode("user.%FF"), None);
+  }
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn set_xattrs_rejected() {
+    let path = std::env::temp_dir().join("mtz_set_xattrs_rejected");
+    fs::write(&path, b"").unwrap();
+    if xattr::set(&path, "user.probe", b"").is_err() {
+      // no user xattrs on this filesystem
+      fs::remove_file(&path).unwrap();
+      return
+    }
+    // names outside the user namespace are left alone, and names the
+    // kernel refuses are returned rather than failing
+    let xattrs = [("user.a".into(), b"1".to_vec()), ("bogus.b".into(), b"2".to_vec())];
+    assert!(set_xattrs(&path, &xattrs, false).unwrap().is_empty());
+    assert_eq!(xattr::get(&path, "user.a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(set_xattrs(&path, &xattrs, true).unwrap(), [OsString::from("bogus.b")]);
+    fs::remove_file(&path).unwrap();
+  }
+  #[cfg(unix)]
+  #[test]
+  fn preserve() {
+    use crate::{pack, unpack, UnpackOptions};
+    use std::os::unix::fs::PermissionsExt;
+    // xattrs, setuid bits and nanosecond mtimes, audio included
+    let tmp = std::env::temp_dir().join("mtz_preserve");
+    let _ = fs::remove_dir_all(&tmp);
+    let meta = tmp.join("meta");
+    fs::create_dir_all(meta.join("d")).unwrap();
+    fs::write(meta.join("run"), b"#!/bin/sh\n").unwrap();
+    fs::set_permissions(meta.join("run"), fs::Permissions::from_mode(0o4755)).unwrap();
+    let spec = hound::WavSpec { channels: 2, sample_rate: 44100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    let mut w = hound::WavWriter::create(meta.join("tone.wav"), spec).unwrap();
+    for i in 0..50000 {
+      let s = ((i as f64 / 40.0).sin() * 10000.0) as i16;
+      w.write_sample(s).unwrap();
+      w.write_sample(s / 3).unwrap();
+    }
+    w.finalize().unwrap();
+    // user xattrs may be unsupported where the tests run
+    let xattrs = ["run", "tone.wav"].iter().all(|f| xattr::set(meta.join(f), "user.tag", b"red\nblue").is_ok());
+    let times = [("run", FileTime::from_unix_time(1700000000, 123456789)),
+		 ("tone.wav", FileTime::from_unix_time(1700000001, 1)),
+		 ("d", FileTime::from_unix_time(1600000000, 500))];
+    for (f, t) in times {
+      filetime::set_file_mtime(meta.join(f), t).unwrap();
+    }
+    let opts = Options::new().audio(true).xattrs(true).precise_mtime(true);
+    pack(&meta, &tmp.join("meta.tar.zst"), &opts).unwrap();
+    let preserve = UnpackOptions::new().xattrs(true).permissions(true).precise_mtime(true);
+    unpack(&tmp.join("meta.tar.zst"), &tmp.join("out"), &preserve).unwrap();
+    unpack(&tmp.join("meta.tar.zst"), &tmp.join("out2"), &UnpackOptions::new()).unwrap();
+    for (f, t) in times {
+      let mtime = |dir: &str| FileTime::from_last_modification_time(&fs::metadata(tmp.join(dir).join("meta").join(f)).unwrap());
+      assert_eq!(mtime("out"), t);
+      assert_eq!(mtime("out2"), FileTime::from_unix_time(t.unix_seconds(), 0));
+    }
+    let mode = |dir: &str| fs::metadata(tmp.join(dir).join("meta/run")).unwrap().permissions().mode() & 0o7777;
+    assert_eq!((mode("out"), mode("out2")), (0o4755, 0o755));
+    assert_eq!(fs::read(tmp.join("out/meta/tone.wav")).unwrap(), fs::read(meta.join("tone.wav")).unwrap());
+    if xattrs {
+      for f in ["run", "tone.wav"] {
+	assert_eq!(xattr::get(tmp.join("out/meta").join(f), "user.tag").unwrap(), Some(b"red\nblue".to_vec()));
+	assert_eq!(xattr::get(tmp.join("out2/meta").join(f), "user.tag").unwrap(), None);
+      }
+    }
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+}
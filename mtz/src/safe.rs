@@ -0,0 +1,547 @@
+//! safe --- extraction policies for untrusted archives
+use crate::crypt::Secret;
+use crate::dict::Dictionary;
+use crate::{audio, meta, Error, Result};
+use filetime::FileTime;
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::{fmt, fs};
+
+/// What to do with symlink and hard link entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Symlinks {
+  /// Skip all links.
+  Skip,
+  /// Extract links whose target stays inside the output directory.
+  #[default]
+  Inside,
+  /// Extract all links.
+  Allow,
+}
+
+impl std::str::FromStr for Symlinks {
+  type Err = Error;
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_lowercase().as_str() {
+      "skip" => Ok(Symlinks::Skip),
+      "inside" => Ok(Symlinks::Inside),
+      "allow" => Ok(Symlinks::Allow),
+      _ => Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown symlink policy: {}. valid = [skip, inside, allow]", s)))),
+    }
+  }
+}
+
+/// What to do when an entry already exists in the output directory.
+/// Existing directories are always reused.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overwrite {
+  /// Keep existing files.
+  #[default]
+  Never,
+  /// Replace existing files which are older than the entry.
+  Newer,
+  /// Replace existing files.
+  Always,
+}
+
+impl std::str::FromStr for Overwrite {
+  type Err = Error;
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_lowercase().as_str() {
+      "never" => Ok(Overwrite::Never),
+      "newer" => Ok(Overwrite::Newer),
+      "always" => Ok(Overwrite::Always),
+      _ => Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown overwrite policy: {}. valid = [never, newer, always]", s)))),
+    }
+  }
+}
+
+/// Options for unpack, extract and decompress.
+#[derive(Clone, Debug, Default)]
+pub struct UnpackOptions {
+  symlinks: Symlinks,
+  overwrite: Overwrite,
+  dicts: Vec<Dictionary>,
+  pub(crate) secret: Option<Secret>,
+  xattrs: bool,
+  all_xattrs: bool,
+  permissions: bool,
+  precise_mtime: bool,
+}
+
+impl UnpackOptions {
+  pub fn new() -> Self {
+    UnpackOptions::default()
+  }
+  pub fn symlinks(mut self, symlinks: Symlinks) -> Self {
+    self.symlinks = symlinks;
+    self
+  }
+  pub fn overwrite(mut self, overwrite: Overwrite) -> Self {
+    self.overwrite = overwrite;
+    self
+  }
+  /// Add a dictionary to decompress with. The one matching the
+  /// dictionary ID of the archive is used.
+  pub fn dict(mut self, dict: Dictionary) -> Self {
+    self.dicts.push(dict);
+    self
+  }
+  pub fn dicts(&self) -> &[Dictionary] {
+    &self.dicts
+  }
+  /// Restore the extended attributes stored with entries.
+  pub fn xattrs(mut self, xattrs: bool) -> Self {
+    self.xattrs = xattrs;
+    self
+  }
+  /// Restore extended attributes outside the user namespace too, such
+  /// as `security.*` and `trusted.*`, which mostly need root.
+  pub fn all_xattrs(mut self, all_xattrs: bool) -> Self {
+    self.all_xattrs = all_xattrs;
+    self
+  }
+  /// Restore the setuid, setgid and sticky bits of entries too, not
+  /// only their read, write and execute bits.
+  pub fn permissions(mut self, permissions: bool) -> Self {
+    self.permissions = permissions;
+    self
+  }
+  /// Restore modification times with the nanoseconds stored with
+  /// entries, rather than whole seconds.
+  pub fn precise_mtime(mut self, precise_mtime: bool) -> Self {
+    self.precise_mtime = precise_mtime;
+    self
+  }
+  /// Decrypt encrypted inputs with SECRET.
+  pub fn secret(mut self, secret: Option<Secret>) -> Self {
+    self.secret = secret;
+    self
+  }
+}
+
+/// Why an entry wasn't extracted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reason {
+  Absolute,
+  Traversal,
+  Link,
+  LinkOutside(PathBuf),
+  Exists,
+  NotNewer,
+  /// The entry was extracted without this extended attribute.
+  Xattr(OsString),
+}
+
+impl fmt::Display for Reason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Reason::Absolute => write!(f, "absolute path"),
+      Reason::Traversal => write!(f, "path outside the output directory"),
+      Reason::Link => write!(f, "links are skipped"),
+      Reason::LinkOutside(t) => write!(f, "link target outside the output directory: {}", t.display()),
+      Reason::Exists => write!(f, "file exists"),
+      Reason::NotNewer => write!(f, "existing file is not older"),
+      Reason::Xattr(name) => write!(f, "extended attribute not restored: {}", name.to_string_lossy()),
+    }
+  }
+}
+
+/// An entry, or an extended attribute of one, which wasn't extracted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Skipped {
+  pub path: PathBuf,
+  pub reason: Reason,
+}
+
+impl fmt::Display for Skipped {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "skipped {}: {}", self.path.display(), self.reason)
+  }
+}
+
+/// Result of unpacking: the paths extracted, the entries skipped and
+/// the files deleted by increments.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Unpacked {
+  pub extracted: Vec<PathBuf>,
+  pub skipped: Vec<Skipped>,
+  pub deleted: Vec<PathBuf>,
+  /// Directories to set the mtimes of once their entries are written.
+  pub(crate) dirs: Vec<(PathBuf, FileTime)>,
+}
+
+impl Unpacked {
+  pub(crate) fn skip(&mut self, path: &Path, reason: Reason) {
+    self.skipped.push(Skipped { path: path.to_path_buf(), reason });
+  }
+}
+
+/// Whether PATH is relative and stays inside the root.
+pub(crate) fn is_inside(path: &Path) -> bool {
+  depth(path).is_some()
+}
+
+/// Depth of PATH below the root after resolving `.` and `..`, or None
+/// if it leaves the root at any point or is absolute.
+fn depth(path: &Path) -> Option<usize> {
+  let mut depth = 0usize;
+  for c in path.components() {
+    match c {
+      Component::Normal(_) => depth += 1,
+      Component::CurDir => (),
+      Component::ParentDir => depth = depth.checked_sub(1)?,
+      Component::RootDir | Component::Prefix(_) => return None,
+    }
+  }
+  Some(depth)
+}
+
+/// PATH with the links on disk followed as far as it exists, and `.`
+/// and `..` applied past that. Dangling links are left as they are.
+pub(crate) fn resolve(path: &Path) -> io::Result<PathBuf> {
+  let mut res = PathBuf::new();
+  for c in std::path::absolute(path)?.components() {
+    match c {
+      Component::CurDir => (),
+      Component::ParentDir => {
+	res.pop();
+      },
+      c => {
+	res.push(c);
+	if fs::symlink_metadata(&res).map(|m| m.file_type().is_symlink()).unwrap_or(false) {
+	  if let Ok(real) = res.canonicalize() {
+	    res = real;
+	  }
+	}
+      },
+    }
+  }
+  Ok(res)
+}
+
+/// The real directory DST and the parent of PATH below it resolve to,
+/// following the links already extracted.
+fn real_parent(dst: &Path, path: &Path) -> io::Result<(PathBuf, PathBuf)> {
+  let root = resolve(dst)?;
+  let path: PathBuf = path.components().filter(|c| *c != Component::CurDir).collect();
+  let parent = match path.parent() {
+    Some(parent) => resolve(&root.join(parent))?,
+    None => root.clone(),
+  };
+  Ok((root, parent))
+}
+
+/// Whether the parent of PATH below DST stays inside DST, following the
+/// links on disk.
+pub(crate) fn parent_inside(dst: &Path, path: &Path) -> io::Result<bool> {
+  let (root, parent) = real_parent(dst, path)?;
+  Ok(parent.starts_with(root))
+}
+
+/// Check whether OPTS allow writing PATH, with modification time MTIME,
+/// to DST. Replaced files are removed first, so a link already at the
+/// destination is never written through.
+pub(crate) fn check_dst(dst: &Path, mtime: u64, opts: &UnpackOptions) -> Result<Option<Reason>> {
+  let meta = match fs::symlink_metadata(dst) {
+    Ok(meta) if meta.is_dir() => return Ok(None),
+    Ok(meta) => meta,
+    Err(_) => return Ok(None),
+  };
+  let replace = match opts.overwrite {
+    Overwrite::Never => return Ok(Some(Reason::Exists)),
+    Overwrite::Newer => meta.modified()?
+      .duration_since(std::time::UNIX_EPOCH)
+      .map(|d| mtime > d.as_secs())
+      .unwrap_or(true),
+    Overwrite::Always => true,
+  };
+  if !replace {
+    return Ok(Some(Reason::NotNewer))
+  }
+  fs::remove_file(dst)?;
+  Ok(None)
+}
+
+/// Why the entry path PATH can't be used, if it is absolute or has `..`
+/// components.
+pub(crate) fn check_path(path: &Path) -> Option<Reason> {
+  if path.has_root() || matches!(path.components().next(), Some(Component::Prefix(_))) {
+    Some(Reason::Absolute)
+  } else if path.components().any(|c| c == Component::ParentDir) {
+    Some(Reason::Traversal)
+  } else {
+    None
+  }
+}
+
+/// A link entry: its target, and whether it is a symlink rather than a
+/// hard link.
+pub(crate) type Link<'a> = Option<(&'a Path, bool)>;
+
+/// Check whether OPTS allow extracting the entry at PATH, a link if
+/// LINK is set, with modification time MTIME into DST, recording the
+/// reason in RES if not. Tar and zip entries go through the same checks.
+pub(crate) fn allow(path: &Path, link: Link, mtime: u64, dst: &Path, opts: &UnpackOptions, res: &mut Unpacked) -> Result<bool> {
+  if let Some(reason) = check_path(path) {
+    res.skip(path, reason);
+    return Ok(false)
+  }
+  // links extracted earlier may lead the parent anywhere
+  let (root, parent) = real_parent(dst, path)?;
+  if !parent.starts_with(&root) {
+    res.skip(path, Reason::Traversal);
+    return Ok(false)
+  }
+  if let Some((target, symlink)) = link {
+    // symlinks are relative to the directory holding them, hard links
+    // to the root
+    let base = if symlink { &parent } else { &root };
+    let inside = !target.has_root() && resolve(&base.join(target))?.starts_with(&root);
+    match opts.symlinks {
+      Symlinks::Skip => {
+	res.skip(path, Reason::Link);
+	return Ok(false)
+      },
+      Symlinks::Inside if !inside => {
+	res.skip(path, Reason::LinkOutside(target.to_path_buf()));
+	return Ok(false)
+      },
+      _ => (),
+    }
+  }
+  if let Some(reason) = check_dst(&dst.join(path), mtime, opts)? {
+    res.skip(path, reason);
+    return Ok(false)
+  }
+  Ok(true)
+}
+
+/// Create the parent directories of PATH below DST. Returns false,
+/// without creating anything, if the parent leads outside DST through
+/// a link, as `unpack_in` checks for tar entries.
+pub(crate) fn create_parent(dst: &Path, path: &Path) -> Result<bool> {
+  let (root, parent) = real_parent(dst, path)?;
+  if !parent.starts_with(&root) {
+    return Ok(false)
+  }
+  fs::create_dir_all(parent)?;
+  Ok(true)
+}
+
+/// Set the modification time MTIME and permissions MODE of a file
+/// written from an entry.
+pub(crate) fn set_times(file: &fs::File, mtime: u64, mode: Option<u32>) -> io::Result<()> {
+  file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime))?;
+  #[cfg(unix)]
+  if let Some(mode) = mode {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+  }
+  #[cfg(not(unix))]
+  let _ = mode;
+  Ok(())
+}
+
+/// Extract ENTRY into DST if OPTS allow it, recording the result in RES.
+/// Returns true if the entry was extracted.
+pub(crate) fn unpack_entry<R: Read>(entry: &mut tar::Entry<R>, dst: &Path, opts: &UnpackOptions, res: &mut Unpacked) -> Result<bool> {
+  let path = entry.path()?.to_path_buf();
+  let kind = entry.header().entry_type();
+  let target = entry.link_name()?.map(|t| t.to_path_buf()).unwrap_or_default();
+  let link = (kind.is_symlink() || kind.is_hard_link()).then_some((target.as_path(), kind.is_symlink()));
+  if !allow(&path, link, entry.header().mtime().unwrap_or(0), dst, opts, res)? {
+    return Ok(false)
+  }
+  let ext = meta::read(entry)?;
+  let out = dst.join(&path);
+  if audio::encoded_size(entry)?.is_some() {
+    if !create_parent(dst, &path)? {
+      res.skip(&path, Reason::Traversal);
+      return Ok(false)
+    }
+    audio::unpack(entry, &out)?;
+    set_mode(&out, entry.header().mode()?, opts.permissions)?;
+  } else {
+    // tar-rs only knows raw xattr records
+    entry.set_unpack_xattrs(false);
+    entry.set_preserve_permissions(opts.permissions);
+    entry.unpack_in(dst)?;
+  }
+  if opts.xattrs && !kind.is_symlink() {
+    for name in meta::set_xattrs(&out, &ext.xattrs, opts.all_xattrs)? {
+      res.skip(&path, Reason::Xattr(name));
+    }
+  }
+  let mtime = match ext.mtime {
+    Some(mtime) if opts.precise_mtime => mtime,
+    _ => FileTime::from_unix_time(entry.header().mtime().unwrap_or(0) as i64, 0),
+  };
+  if kind.is_dir() {
+    res.dirs.push((out, mtime));
+  } else if kind.is_file() {
+    filetime::set_file_mtime(&out, mtime)?;
+  }
+  res.extracted.push(path);
+  Ok(true)
+}
+
+/// Set the permissions of PATH to MODE, with the setuid, setgid and
+/// sticky bits if ALL.
+fn set_mode(path: &Path, mode: u32, all: bool) -> io::Result<()> {
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & if all { 0o7777 } else { 0o777 }))?;
+  }
+  #[cfg(not(unix))]
+  let _ = (path, mode, all);
+  Ok(())
+}
+
+/// Set the mtimes of the directories unpacked into RES, which writing
+/// their entries changed.
+pub(crate) fn set_dir_times(res: &mut Unpacked) -> io::Result<()> {
+  for (path, mtime) in res.dirs.drain(..).rev() {
+    filetime::set_file_mtime(path, mtime)?;
+  }
+  Ok(())
+}
+
+/// Extract the zip entry FILE into DST if OPTS allow it, as with
+/// `unpack_entry`.
+pub(crate) fn unpack_zip_entry(file: &mut zip::read::ZipFile<'_>, dst: &Path, opts: &UnpackOptions, res: &mut Unpacked) -> Result<bool> {
+  let path = PathBuf::from(file.name());
+  let mtime = zip_mtime(file);
+  let mut target = String::new();
+  if file.is_symlink() {
+    file.read_to_string(&mut target)?;
+  }
+  let link = file.is_symlink().then_some((Path::new(&target), true));
+  if !allow(&path, link, mtime, dst, opts, res)? {
+    return Ok(false)
+  }
+  if !create_parent(dst, &path)? {
+    res.skip(&path, Reason::Traversal);
+    return Ok(false)
+  }
+  let out = dst.join(&path);
+  if file.is_dir() {
+    fs::create_dir_all(&out)?;
+    res.dirs.push((out, FileTime::from_unix_time(mtime as i64, 0)));
+  } else if file.is_symlink() {
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&target, &out)?;
+    #[cfg(not(unix))]
+    fs::write(&out, &target)?;
+  } else {
+    let mut f = fs::File::create(&out)?;
+    io::copy(file, &mut f)?;
+    set_times(&f, mtime, file.unix_mode())?;
+  }
+  res.extracted.push(path);
+  Ok(true)
+}
+
+/// Modification time of a zip entry, taking its local time as UTC.
+pub(crate) fn zip_mtime(file: &zip::read::ZipFile<'_>) -> u64 {
+  let Some(t) = file.last_modified() else { return 0 };
+  // days from civil date, see http://howardhinnant.github.io/date_algorithms.html
+  let (m, d) = (t.month() as i64, t.day() as i64);
+  let y = t.year() as i64 - if m <= 2 { 1 } else { 0 };
+  let era = y / 400;
+  let yoe = y - era * 400;
+  let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  let days = era * 146097 + doe - 719468;
+  (days * 86400 + t.hour() as i64 * 3600 + t.minute() as i64 * 60 + t.second() as i64).max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn policies() {
+    assert_eq!(depth(Path::new("a/./b/../c")), Some(2));
+    assert_eq!(depth(Path::new("a/../../c")), None);
+    assert_eq!(depth(Path::new("/etc")), None);
+
+    let tmp = std::env::temp_dir().join("mtz_policies");
+    let _ = fs::remove_dir_all(&tmp);
+    fs::create_dir_all(&tmp).unwrap();
+    let mut tar = tar::Builder::new(vec![]);
+    let mut add_link = |path: &str, target: &str| {
+      let mut header = tar::Header::new_gnu();
+      header.set_entry_type(tar::EntryType::Symlink);
+      header.set_size(0);
+      tar.append_link(&mut header, path, target).unwrap();
+    };
+    add_link("in", "sub/../x");
+    add_link("sub/out", "../../x");
+    add_link("abs", "/etc/passwd");
+    // the tar crate refuses to write `..` paths, so set the name directly
+    let mut header = tar::Header::new_gnu();
+    header.set_size(2);
+    header.as_old_mut().name[..5].copy_from_slice(b"../tx");
+    header.set_cksum();
+    tar.append(&header, &b"hi"[..]).unwrap();
+    let bytes = tar.into_inner().unwrap();
+
+    let unpack = |opts: UnpackOptions| {
+      let mut res = Unpacked::default();
+      for entry in tar::Archive::new(&bytes[..]).entries().unwrap() {
+	unpack_entry(&mut entry.unwrap(), &tmp, &opts, &mut res).unwrap();
+      }
+      res
+    };
+    let res = unpack(UnpackOptions::new());
+    assert_eq!(res.extracted, [PathBuf::from("in")]);
+    let reasons: Vec<Reason> = res.skipped.into_iter().map(|s| s.reason).collect();
+    assert_eq!(reasons, [Reason::LinkOutside("../../x".into()), Reason::LinkOutside("/etc/passwd".into()), Reason::Traversal]);
+    assert_eq!(unpack(UnpackOptions::new()).skipped[0].reason, Reason::Exists);
+    assert_eq!(unpack(UnpackOptions::new().symlinks(Symlinks::Skip)).skipped[0].reason, Reason::Link);
+    assert_eq!(unpack(UnpackOptions::new().overwrite(Overwrite::Newer)).skipped[0].reason, Reason::NotNewer);
+    assert_eq!(unpack(UnpackOptions::new().overwrite(Overwrite::Always)).extracted[0], PathBuf::from("in"));
+    fs::remove_dir_all(&tmp).unwrap();
+
+    // links through links extracted before, and files through links
+    // leading outside
+    let dst = tmp.join("dst");
+    fs::create_dir_all(&dst).unwrap();
+    let mut tar = tar::Builder::new(vec![]);
+    let mut add_link = |path: &str, target: &str| {
+      let mut header = tar::Header::new_gnu();
+      header.set_entry_type(tar::EntryType::Symlink);
+      header.set_size(0);
+      tar.append_link(&mut header, path, target).unwrap();
+    };
+    add_link("a", ".");
+    add_link("a/b", "..");
+    add_link("a/c", "a/a/a/../..");
+    add_link("a/d", "a/a/sub");
+    let mut header = tar::Header::new_gnu();
+    header.set_size(2);
+    header.set_cksum();
+    tar.append_data(&mut header, "a/a/f", &b"hi"[..]).unwrap();
+    let bytes = tar.into_inner().unwrap();
+    let mut res = Unpacked::default();
+    for entry in tar::Archive::new(&bytes[..]).entries().unwrap() {
+      unpack_entry(&mut entry.unwrap(), &dst, &UnpackOptions::new(), &mut res).unwrap();
+    }
+    assert_eq!(res.extracted, [PathBuf::from("a"), PathBuf::from("a/d"), PathBuf::from("a/a/f")]);
+    assert_eq!(res.skipped.iter().map(|s| s.path.to_str().unwrap()).collect::<Vec<_>>(), ["a/b", "a/c"]);
+    assert_eq!(fs::read(dst.join("f")).unwrap(), b"hi");
+
+    fs::create_dir_all(tmp.join("outside")).unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("../outside", dst.join("out")).unwrap();
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_dir("../outside", dst.join("out")).unwrap();
+    assert!(!create_parent(&dst, Path::new("out/x/y/f")).unwrap());
+    assert!(!tmp.join("outside/x").exists());
+    assert!(create_parent(&dst, Path::new("a/x/y/f")).unwrap());
+    assert!(dst.join("x/y").is_dir());
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+}
@@ -0,0 +1,282 @@
+//! seekable --- archives made of independent frames with a seek table
+//!
+//! A seekable archive starts a new zstd frame for every tar entry, and
+//! ends with an index of entry offsets and a seek table in the zstd
+//! seekable format. See
+//! [https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md]
+//!
+//! Both are skippable frames, so seekable archives decode as usual with
+//! any zstd decoder.
+use crate::{frame, Error, Options, Result};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Magic number of the skippable frame holding the seek table.
+pub const SEEK_TABLE_MAGIC: u32 = frame::SKIPPABLE_MAGIC | 0xE;
+/// Magic number at the end of the seek table footer.
+pub const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+/// Magic number of the skippable frame mapping entry paths to their
+/// offset in the tarball.
+pub const INDEX_MAGIC: u32 = frame::SKIPPABLE_MAGIC | 0xC;
+/// Largest amount of data compressed into a single frame. Bigger
+/// entries are split over several frames.
+pub const MAX_FRAME_SIZE: u64 = 1 << 28;
+
+/// A writer which counts the bytes written through it.
+struct Counter<W> {
+  inner: W,
+  count: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = self.inner.write(buf)?;
+    self.count += n as u64;
+    Ok(n)
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Compress into independent frames, recording a seek table entry for
+/// each frame.
+pub struct SeekableWriter<'a, W: Write> {
+  opts: Options,
+  // exactly one of these is set, depending on whether a frame is open
+  idle: Option<Counter<W>>,
+  encoder: Option<zstd::Encoder<'a, Counter<W>>>,
+  table: Vec<(u32, u32)>,
+  frame_len: u64,
+  offset: u64,
+  index: BTreeMap<String, u64>,
+}
+
+impl<'a, W: Write> SeekableWriter<'a, W> {
+  /// Start a seekable file on W, beginning with the settings frame.
+  pub fn new(w: W, opts: &Options) -> Result<Self> {
+    let mut w = SeekableWriter {
+      opts: opts.clone(),
+      idle: Some(Counter { inner: w, count: 0 }),
+      encoder: None,
+      table: vec![],
+      frame_len: 0,
+      offset: 0,
+      index: BTreeMap::new(),
+    };
+    let settings = serde_json::to_vec(&opts.settings()).map_err(io::Error::from)?;
+    w.write_skippable(crate::SETTINGS_MAGIC, &settings)?;
+    Ok(w)
+  }
+
+  /// Close the current frame, and record PATH as starting at the
+  /// current offset of the uncompressed stream.
+  pub fn mark(&mut self, path: &str) -> io::Result<()> {
+    self.end_frame()?;
+    self.index.insert(path.to_string(), self.offset);
+    Ok(())
+  }
+
+  fn end_frame(&mut self) -> io::Result<()> {
+    if let Some(encoder) = self.encoder.take() {
+      let mut w = encoder.finish()?;
+      self.table.push((w.count as u32, self.frame_len as u32));
+      w.count = 0;
+      self.frame_len = 0;
+      self.idle = Some(w);
+    }
+    Ok(())
+  }
+
+  fn write_skippable(&mut self, magic: u32, data: &[u8]) -> io::Result<()> {
+    self.end_frame()?;
+    let w = self.idle.as_mut().unwrap();
+    frame::write_skippable(w, magic, data)?;
+    self.table.push((w.count as u32, 0));
+    w.count = 0;
+    Ok(())
+  }
+
+  /// Close the last frame and write the index and seek table.
+  pub fn finish(mut self) -> Result<W> {
+    let index = serde_json::to_vec(&self.index).map_err(io::Error::from)?;
+    self.write_skippable(INDEX_MAGIC, &index)?;
+    let mut table = vec![];
+    for (c, d) in &self.table {
+      table.extend(c.to_le_bytes());
+      table.extend(d.to_le_bytes());
+    }
+    table.extend((self.table.len() as u32).to_le_bytes());
+    table.push(0);
+    table.extend(SEEKABLE_MAGIC.to_le_bytes());
+    let mut w = self.idle.take().unwrap();
+    frame::write_skippable(&mut w, SEEK_TABLE_MAGIC, &table)?;
+    Ok(w.inner)
+  }
+}
+
+impl<W: Write> Write for SeekableWriter<'_, W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0)
+    }
+    if self.encoder.is_none() {
+      let w = self.idle.take().unwrap();
+      self.encoder = Some(self.opts.frame_encoder(w)?);
+    }
+    let len = buf.len().min((MAX_FRAME_SIZE - self.frame_len) as usize);
+    let n = self.encoder.as_mut().unwrap().write(&buf[..len])?;
+    self.frame_len += n as u64;
+    self.offset += n as u64;
+    if self.frame_len == MAX_FRAME_SIZE {
+      self.end_frame()?;
+    }
+    Ok(n)
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    match self.encoder.as_mut() {
+      Some(e) => e.flush(),
+      None => self.idle.as_mut().unwrap().flush(),
+    }
+  }
+}
+
+/// Position of a frame in the compressed file and of its contents in
+/// the uncompressed stream.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frame {
+  pub offset: u64,
+  pub compressed: u32,
+  pub content_offset: u64,
+  pub decompressed: u32,
+}
+
+/// The seek table at the end of a seekable file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SeekTable {
+  pub frames: Vec<Frame>,
+}
+
+impl SeekTable {
+  /// Read the seek table at the end of R. Returns None if R doesn't end
+  /// with one.
+  pub fn read<R: Read + Seek>(r: &mut R) -> Result<Option<Self>> {
+    let len = r.seek(SeekFrom::End(0))?;
+    if len < 17 {
+      return Ok(None)
+    }
+    let mut footer = [0u8; 9];
+    r.seek(SeekFrom::End(-9))?;
+    r.read_exact(&mut footer)?;
+    if u32::from_le_bytes(footer[5..9].try_into().unwrap()) != SEEKABLE_MAGIC {
+      return Ok(None)
+    }
+    let count = u32::from_le_bytes(footer[0..4].try_into().unwrap()) as u64;
+    let entry_size = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+    let size = count * entry_size + 9;
+    let invalid = || Error::Io(io::Error::new(io::ErrorKind::InvalidData, "invalid seek table"));
+    if size + 8 > len {
+      return Err(invalid())
+    }
+    r.seek(SeekFrom::End(-(size as i64) - 8))?;
+    match frame::read_skippable(r)? {
+      Some((SEEK_TABLE_MAGIC, data)) if data.len() as u64 == size => {
+	let mut table = SeekTable::default();
+	let (mut offset, mut content_offset) = (0, 0);
+	for e in data[..data.len() - 9].chunks(entry_size as usize) {
+	  let compressed = u32::from_le_bytes(e[0..4].try_into().unwrap());
+	  let decompressed = u32::from_le_bytes(e[4..8].try_into().unwrap());
+	  table.frames.push(Frame { offset, compressed, content_offset, decompressed });
+	  offset += compressed as u64;
+	  content_offset += decompressed as u64;
+	}
+	Ok(Some(table))
+      },
+      _ => Err(invalid()),
+    }
+  }
+
+  /// The frame holding position POS of the uncompressed stream.
+  pub fn frame_at(&self, pos: u64) -> Option<&Frame> {
+    self.frames.iter()
+      .find(|f| f.content_offset <= pos && pos < f.content_offset + f.decompressed as u64)
+  }
+
+  /// Read the entry index from R, searching the skippable frames from
+  /// the end.
+  pub fn index<R: Read + Seek>(&self, r: &mut R) -> Result<Option<BTreeMap<String, u64>>> {
+    for f in self.frames.iter().rev().filter(|f| f.decompressed == 0) {
+      r.seek(SeekFrom::Start(f.offset))?;
+      if let Some((INDEX_MAGIC, data)) = frame::read_skippable(r)? {
+	return Ok(Some(serde_json::from_slice(&data).map_err(io::Error::from)?))
+      }
+    }
+    Ok(None)
+  }
+
+  /// A decoder over R positioned at offset POS of the uncompressed
+  /// stream, which decompresses only from the frame holding POS on,
+  /// using DICT if not empty.
+  pub fn reader_at<R: Read + Seek>(&self, mut r: R, pos: u64, dict: &[u8]) -> Result<zstd::Decoder<'static, io::BufReader<R>>> {
+    let frame = self.frame_at(pos)
+      .ok_or_else(|| Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("offset {} is past the end of the archive", pos))))?;
+    r.seek(SeekFrom::Start(frame.offset))?;
+    let mut decoder = zstd::Decoder::with_dictionary(io::BufReader::new(r), dict)?;
+    io::copy(&mut (&mut decoder).take(pos - frame.content_offset), &mut io::sink())?;
+    Ok(decoder)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn seek_table() {
+    let mut w = SeekableWriter::new(vec![], &Options::new()).unwrap();
+    w.mark("a").unwrap();
+    w.write_all(b"hello ").unwrap();
+    w.mark("b").unwrap();
+    w.write_all(b"world").unwrap();
+    let bytes = w.finish().unwrap();
+
+    let mut dec = zstd::Decoder::new(&bytes[..]).unwrap();
+    let mut all = String::new();
+    dec.read_to_string(&mut all).unwrap();
+    assert_eq!(all, "hello world");
+
+    let mut r = io::Cursor::new(&bytes);
+    let table = SeekTable::read(&mut r).unwrap().unwrap();
+    // settings, 2 data frames, index
+    assert_eq!(table.frames.len(), 4);
+    assert_eq!(table.frame_at(8).unwrap().decompressed, 5);
+    let index = table.index(&mut r).unwrap().unwrap();
+    assert_eq!(index["b"], 6);
+    let mut rest = String::new();
+    table.reader_at(r, 8, &[]).unwrap().read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "rld");
+    assert!(SeekTable::read(&mut io::Cursor::new(&bytes[..bytes.len() - 1])).unwrap().is_none());
+  }
+  #[test]
+  fn extract_entries() {
+    use crate::{extract, pack, verify, Error, UnpackOptions};
+    let tmp = std::env::temp_dir().join("mtz_extract");
+    let _ = std::fs::remove_dir_all(&tmp);
+    let src = tmp.join("src");
+    std::fs::create_dir_all(src.join("sub")).unwrap();
+    std::fs::write(src.join("a.txt"), b"hello").unwrap();
+    std::fs::write(src.join("sub/b.bin"), vec![7u8; 100000]).unwrap();
+    let uo = UnpackOptions::new();
+    let seekable = tmp.join("seekable.tar.zst");
+    pack(&src, &seekable, &Options::new().seekable(true)).unwrap();
+    assert!(crate::info(&seekable, &uo).unwrap().frames.is_some());
+    assert!(verify(&seekable, &uo).unwrap().problems.is_empty());
+    assert_eq!(extract(&seekable, "src/sub", tmp.join("out"), &uo).unwrap().extracted.len(), 2);
+    assert_eq!(std::fs::read(tmp.join("out/src/sub/b.bin")).unwrap(), vec![7u8; 100000]);
+    assert!(!tmp.join("out/src/a.txt").exists());
+    assert!(matches!(extract(&seekable, "src/nope", &tmp, &uo), Err(Error::NotFound(_))));
+    let archive = tmp.join("src.tar.zst");
+    pack(&src, &archive, &Options::new()).unwrap();
+    assert!(matches!(extract(&archive, "src/a.txt", &tmp, &uo), Err(Error::NotSeekable(_))));
+    std::fs::remove_dir_all(&tmp).unwrap();
+  }
+}
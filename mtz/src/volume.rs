@@ -0,0 +1,407 @@
+//! volume --- archives split into numbered volumes
+//!
+//! A split archive NAME is written as NAME.001, NAME.002, ... which
+//! concatenate back into the original stream. Every volume but the last
+//! holds exactly the volume size recorded in the settings frame, and the
+//! last is always shorter, so missing and truncated volumes can be told
+//! apart from a complete set.
+use crate::crypt::{self, Secret};
+use crate::{frame, Error, Result, Settings, SETTINGS_MAGIC};
+use std::ffi::OsString;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::{fmt, fs};
+
+/// Path of volume N (counting from 1) of the archive at BASE.
+pub fn volume_path(base: &Path, n: usize) -> PathBuf {
+  let mut name = OsString::from(base.as_os_str());
+  name.push(format!(".{:03}", n));
+  PathBuf::from(name)
+}
+
+/// The archive path of a volume path, by removing a numeric extension
+/// of three or more digits.
+pub fn base_path(path: &Path) -> Option<PathBuf> {
+  let ext = path.extension()?.to_str()?;
+  if ext.len() >= 3 && ext.chars().all(|c| c.is_ascii_digit()) {
+    Some(path.with_extension(""))
+  } else {
+    None
+  }
+}
+
+/// Whether PATH is `-`, standing for standard input or output.
+pub fn is_stdio(path: &Path) -> bool {
+  path.as_os_str() == "-"
+}
+
+/// Parse a size in bytes with an optional K, M, G or T suffix (powers
+/// of 1024).
+pub fn parse_size(s: &str) -> Result<u64> {
+  let s = s.trim();
+  let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+  let invalid = || Error::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid size: {}", s)));
+  let n: u64 = s[..split].parse().map_err(|_| invalid())?;
+  let shift = match s[split..].to_uppercase().trim_end_matches(['B', 'I']) {
+    "" => 0,
+    "K" => 10,
+    "M" => 20,
+    "G" => 30,
+    "T" => 40,
+    _ => return Err(invalid()),
+  };
+  n.checked_mul(1 << shift).filter(|n| *n > 0).ok_or_else(invalid)
+}
+
+/// A volume which is missing from a split archive or too short.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VolumeProblem {
+  Missing(PathBuf),
+  Truncated(PathBuf),
+}
+
+impl fmt::Display for VolumeProblem {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      VolumeProblem::Missing(p) => write!(f, "missing: {}", p.display()),
+      VolumeProblem::Truncated(p) => write!(f, "truncated: {}", p.display()),
+    }
+  }
+}
+
+/// Writes numbered volumes of at most SIZE bytes each.
+pub struct SplitWriter {
+  base: PathBuf,
+  size: u64,
+  volumes: usize,
+  written: u64,
+  file: Option<BufWriter<fs::File>>,
+}
+
+impl SplitWriter {
+  pub fn new(base: &Path, size: u64) -> Self {
+    SplitWriter { base: base.to_path_buf(), size, volumes: 0, written: 0, file: None }
+  }
+
+  fn next_volume(&mut self) -> io::Result<()> {
+    if let Some(mut f) = self.file.take() {
+      f.flush()?;
+    }
+    self.volumes += 1;
+    self.file = Some(BufWriter::new(fs::File::create(volume_path(&self.base, self.volumes))?));
+    self.written = 0;
+    Ok(())
+  }
+
+  /// Flush the last volume, adding an empty one if the last is full,
+  /// and remove the volumes after it left by an earlier, longer archive.
+  /// Returns the number of volumes written.
+  pub fn finish(mut self) -> Result<usize> {
+    if self.file.is_none() || self.written == self.size {
+      self.next_volume()?;
+    }
+    self.file.take().unwrap().flush()?;
+    for n in self.volumes + 1.. {
+      match fs::remove_file(volume_path(&self.base, n)) {
+	Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+	res => res?,
+      }
+    }
+    Ok(self.volumes)
+  }
+}
+
+impl Write for SplitWriter {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if self.file.is_none() || self.written == self.size {
+      self.next_volume()?;
+    }
+    let len = buf.len().min((self.size - self.written) as usize);
+    let n = self.file.as_mut().unwrap().write(&buf[..len])?;
+    self.written += n as u64;
+    Ok(n)
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    match self.file.as_mut() {
+      Some(f) => f.flush(),
+      None => Ok(()),
+    }
+  }
+}
+
+/// Destination of pack and compress: a single file, volumes or
+/// standard output, which may hold the output encrypted.
+pub enum Output {
+  File(BufWriter<fs::File>),
+  Stdout(BufWriter<io::StdoutLock<'static>>),
+  Split(SplitWriter),
+  Encrypted(Box<crypt::Writer<Output>>),
+}
+
+impl Output {
+  /// Create DST, or write to standard output if DST is `-`.
+  pub fn create(dst: &Path, split: Option<u64>) -> Result<Self> {
+    if is_stdio(dst) {
+      return match split {
+	Some(_) => Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "standard output can't be split into volumes"))),
+	None => Ok(Output::Stdout(BufWriter::new(io::stdout().lock()))),
+      }
+    }
+    match split {
+      Some(size) => Ok(Output::Split(SplitWriter::new(dst, size))),
+      None => Ok(Output::File(BufWriter::new(fs::File::create(dst)?))),
+    }
+  }
+  /// Encrypt everything written to this output with SECRET.
+  pub fn encrypt(self, secret: &Secret) -> Result<Self> {
+    Ok(Output::Encrypted(Box::new(crypt::Writer::new(self, secret)?)))
+  }
+  pub fn finish(self) -> Result<()> {
+    match self {
+      Output::File(mut f) => Ok(f.flush()?),
+      Output::Stdout(mut f) => Ok(f.flush()?),
+      Output::Split(s) => s.finish().map(|_| ()),
+      Output::Encrypted(w) => w.finish()?.finish(),
+    }
+  }
+}
+
+impl Write for Output {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self {
+      Output::File(f) => f.write(buf),
+      Output::Stdout(f) => f.write(buf),
+      Output::Split(s) => s.write(buf),
+      Output::Encrypted(w) => w.write(buf),
+    }
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      Output::File(f) => f.flush(),
+      Output::Stdout(f) => f.flush(),
+      Output::Split(s) => s.flush(),
+      Output::Encrypted(w) => w.flush(),
+    }
+  }
+}
+
+/// Read SRC as a stream: standard input for `-`, or else the file or
+/// the volumes of a split archive.
+pub fn stream(src: &Path) -> Result<Box<dyn Read>> {
+  if is_stdio(src) {
+    Ok(Box::new(io::stdin().lock()))
+  } else {
+    Ok(Box::new(Input::open(src)?))
+  }
+}
+
+/// Find the volumes of the archive at BASE, checking that none are
+/// missing or truncated. Returns an empty list if there is no first
+/// volume.
+pub fn volumes(base: &Path) -> Result<Vec<PathBuf>> {
+  let first = volume_path(base, 1);
+  if !first.is_file() {
+    return Ok(vec![])
+  }
+  // volumes up to the first gap, which shows as a missing volume after
+  // the last one read
+  let paths: Vec<PathBuf> = (1..).map(|n| volume_path(base, n)).take_while(|p| p.is_file()).collect();
+  let last = paths.len();
+  let gap = volume_path(base, last + 2).is_file();
+  let mut problems = vec![];
+  // without a recorded size, a single volume can't be checked
+  let recorded = volume_size(&first)?;
+  let size = match recorded {
+    Some(size) => size,
+    None => fs::metadata(&first)?.len(),
+  };
+  for (i, path) in paths.iter().enumerate() {
+    let len = fs::metadata(path)?.len();
+    if i + 1 < paths.len() && len < size {
+      problems.push(VolumeProblem::Truncated(path.clone()));
+    } else if i + 1 == paths.len() && (gap || (len >= size && (recorded.is_some() || i > 0))) {
+      problems.push(VolumeProblem::Missing(volume_path(base, last + 1)));
+    }
+  }
+  if problems.is_empty() {
+    Ok(paths)
+  } else {
+    Err(Error::Volumes(problems))
+  }
+}
+
+/// The volume size recorded in the settings of the first volume.
+fn volume_size(first: &Path) -> Result<Option<u64>> {
+  let mut file = io::BufReader::new(fs::File::open(first)?);
+  match frame::read_skippable(&mut file) {
+    Ok(Some((SETTINGS_MAGIC, data))) => Ok(serde_json::from_slice::<Settings>(&data).ok().and_then(|s| s.split)),
+    _ => Ok(None),
+  }
+}
+
+/// Source of unpack, list, verify and extract: a single file or the
+/// volumes of a split archive, read as one stream.
+pub struct Input {
+  paths: Vec<PathBuf>,
+  sizes: Vec<u64>,
+  index: usize,
+  file: fs::File,
+}
+
+impl Input {
+  /// Open SRC, or the volumes of SRC if it doesn't exist. SRC may also
+  /// name the first volume.
+  pub fn open(src: &Path) -> Result<Self> {
+    let paths = match base_path(src) {
+      Some(base) if src.is_file() && volume_path(&base, 1) == src => volumes(&base)?,
+      _ if src.is_file() => vec![src.to_path_buf()],
+      _ => volumes(src)?,
+    };
+    if paths.is_empty() {
+      return Err(Error::NotFound(src.to_path_buf()))
+    }
+    let sizes = paths.iter().map(|p| fs::metadata(p).map(|m| m.len())).collect::<io::Result<_>>()?;
+    Ok(Input { file: fs::File::open(&paths[0])?, paths, sizes, index: 0 })
+  }
+
+  /// The first file of the input.
+  pub fn first(&self) -> &Path {
+    &self.paths[0]
+  }
+
+  pub fn len(&self) -> u64 {
+    self.sizes.iter().sum()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl Read for Input {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+      let n = self.file.read(buf)?;
+      if n > 0 || buf.is_empty() || self.index + 1 == self.paths.len() {
+	return Ok(n)
+      }
+      self.index += 1;
+      self.file = fs::File::open(&self.paths[self.index])?;
+    }
+  }
+}
+
+impl Seek for Input {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let start: u64 = self.sizes[..self.index].iter().sum();
+    let target = match pos {
+      SeekFrom::Start(n) => n as i64,
+      SeekFrom::End(n) => self.len() as i64 + n,
+      SeekFrom::Current(n) => (start + self.file.stream_position()?) as i64 + n,
+    };
+    if target < 0 {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the archive"))
+    }
+    let (mut index, mut offset) = (0, target as u64);
+    while index + 1 < self.sizes.len() && offset >= self.sizes[index] {
+      offset -= self.sizes[index];
+      index += 1;
+    }
+    if index != self.index {
+      self.index = index;
+      self.file = fs::File::open(&self.paths[index])?;
+    }
+    self.file.seek(SeekFrom::Start(offset))?;
+    Ok(target as u64)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn split() {
+    assert_eq!(parse_size("3900M").unwrap(), 3900 << 20);
+    assert_eq!(parse_size("12").unwrap(), 12);
+    assert!(parse_size("1X").is_err());
+    assert_eq!(base_path(Path::new("a.tar.zst.002")), Some(PathBuf::from("a.tar.zst")));
+    assert_eq!(base_path(Path::new("a.tar.zst.1000")), Some(PathBuf::from("a.tar.zst")));
+    assert_eq!(base_path(Path::new("a.tar.zst")), None);
+
+    let tmp = std::env::temp_dir().join("mtz_split");
+    let _ = fs::remove_dir_all(&tmp);
+    fs::create_dir_all(&tmp).unwrap();
+    let base = tmp.join("a.bin");
+    let data: Vec<u8> = (0..100u8).collect();
+    let mut w = SplitWriter::new(&base, 25);
+    w.write_all(&data).unwrap();
+    // the last volume would be full, so an empty one is added
+    assert_eq!(w.finish().unwrap(), 5);
+
+    let mut input = Input::open(&base).unwrap();
+    let mut all = vec![];
+    input.read_to_end(&mut all).unwrap();
+    assert_eq!(all, data);
+    input.seek(SeekFrom::Start(60)).unwrap();
+    let mut buf = [0u8; 20];
+    input.read_exact(&mut buf).unwrap();
+    assert_eq!(buf[0], 60);
+    assert_eq!(buf[19], 79);
+    assert_eq!(Input::open(&volume_path(&base, 1)).unwrap().len(), 100);
+
+    // packing again with fewer volumes removes the rest
+    let mut w = SplitWriter::new(&base, 50);
+    w.write_all(&data[..60]).unwrap();
+    assert_eq!(w.finish().unwrap(), 2);
+    assert!(!volume_path(&base, 3).exists());
+    assert_eq!(Input::open(&base).unwrap().len(), 60);
+    let mut w = SplitWriter::new(&base, 25);
+    w.write_all(&data).unwrap();
+    w.finish().unwrap();
+
+    fs::write(volume_path(&base, 3), &data[..10]).unwrap();
+    fs::remove_file(volume_path(&base, 5)).unwrap();
+    match Input::open(&base) {
+      Err(Error::Volumes(p)) => assert_eq!(p, [
+	VolumeProblem::Truncated(volume_path(&base, 3)),
+	VolumeProblem::Missing(volume_path(&base, 5)),
+      ]),
+      _ => panic!("expected volume problems"),
+    }
+    fs::remove_file(volume_path(&base, 2)).unwrap();
+    assert!(matches!(Input::open(&base), Err(Error::Volumes(p)) if p == [VolumeProblem::Missing(volume_path(&base, 2))]));
+
+    // more than 999 volumes
+    let many = tmp.join("many.bin");
+    let mut w = SplitWriter::new(&many, 1);
+    w.write_all(&[1u8; 1000]).unwrap();
+    assert_eq!(w.finish().unwrap(), 1001);
+    assert!(volume_path(&many, 1001).ends_with("many.bin.1001"));
+    assert_eq!(Input::open(&many).unwrap().len(), 1000);
+    fs::remove_file(volume_path(&many, 1000)).unwrap();
+    assert!(matches!(Input::open(&many), Err(Error::Volumes(p)) if p == [VolumeProblem::Missing(volume_path(&many, 1000))]));
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+  #[test]
+  fn split_archive() {
+    use crate::{pack, unpack, verify, Error, Level, Options, UnpackOptions};
+    let tmp = std::env::temp_dir().join("mtz_split_archive");
+    let _ = fs::remove_dir_all(&tmp);
+    let src = tmp.join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    fs::write(src.join("a.txt"), b"hello").unwrap();
+    fs::write(src.join("sub/b.bin"), vec![7u8; 100000]).unwrap();
+    fs::write(src.join("noise.bin"), (0..20000u32).map(|i| (i * 7919 % 251) as u8 ^ (i >> 3) as u8).collect::<Vec<u8>>()).unwrap();
+    let uo = UnpackOptions::new();
+    let split = tmp.join("split.tar.zst");
+    pack(&src, &split, &Options::new().level(Level::Fastest).split(Some(1000))).unwrap();
+    assert!(!split.exists() && volume_path(&split, 2).exists());
+    assert_eq!(crate::info(&split, &uo).unwrap().settings.unwrap().split, Some(1000));
+    assert!(verify(&split, &uo).unwrap().problems.is_empty());
+    unpack(&volume_path(&split, 1), &tmp.join("out"), &uo).unwrap();
+    assert_eq!(fs::read(tmp.join("out/src/noise.bin")).unwrap(), fs::read(src.join("noise.bin")).unwrap());
+    fs::remove_file(volume_path(&split, 2)).unwrap();
+    assert!(matches!(verify(&split, &uo), Err(Error::Volumes(_))));
+    fs::remove_dir_all(&tmp).unwrap();
+  }
+}
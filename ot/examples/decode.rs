@@ -1,10 +1,11 @@
 //! decode --- decode .ot file and print metadata
-use ot::OTWriter;
-use hound::WavReader;
+use ot::OTData;
+use ot::inspect::Inspector;
 
-fn main() {
+fn main() -> std::io::Result<()> {
 //  let file = "examples/tracks/164.75_Paradox_Deep Sleep.ot";
   let file = "sample_chain.ot";
-  let decoded = OTWriter::from_file(file, 44100);
-  println!("{}", decoded);
+  let data = OTData::decode(&std::fs::read(file)?)?;
+  println!("{}", Inspector::new(data, 44100));
+  Ok(())
 }
@@ -0,0 +1,13 @@
+//! export --- create sampler presets and an OP-1 drum kit from a sample chain
+use ot::sampler::Instrument;
+use ot::op1::DrumKit;
+
+fn main() -> std::io::Result<()> {
+  let inst = Instrument::from_ot("sample_chain.wav", "sample_chain.ot")?
+    .root_key(36);
+  inst.write_sfz("sample_chain.sfz")?;
+  inst.write_dspreset("sample_chain.dspreset")?;
+
+  DrumKit::from_ot("sample_chain.wav", "sample_chain.ot")?
+    .write("sample_chain.aif")
+}
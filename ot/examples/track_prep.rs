@@ -13,22 +13,19 @@ fn main() -> std::io::Result<()> {
   for f in std::fs::read_dir(input)? {
     let f = f?.path();
     if let Some(ext) = f.extension() {
-      match ext.to_str().unwrap() {
-	"wav" => {
-	  // first 6 characters of a file_name is the tempo:
-	  // (140.00_ARTIST_TRACK.wav)
-	  let tempo: f32 = f.file_name().unwrap()
-	    .to_str().unwrap()[..6]
-	    .parse().expect("failed to parse tempo from file name");
+      if ext == "wav" {
+	// first 6 characters of a file_name is the tempo:
+	// (140.00_ARTIST_TRACK.wav)
+	let tempo: f32 = f.file_name().unwrap()
+	  .to_str().unwrap()[..6]
+	  .parse().expect("failed to parse tempo from file name");
 
-	  let len: u32 = WavReader::open(&f).unwrap().samples::<i16>().count() as u32;
+	let len: u32 = WavReader::open(&f).unwrap().samples::<i16>().count() as u32;
 
-	  writer = writer.file_name(f.with_extension("ot"))
-	    .tempo(tempo);
+	writer = writer.file_name(f.with_extension("ot"))
+	  .tempo(tempo);
 	
-	  writer.write(len)?;
-	},
-	_ => (),
+	writer.write(len)?;
       }
     }
   }
@@ -0,0 +1,281 @@
+//! chain --- combine multiple wav files into a sample chain with a
+//! matching .ot file
+use crate::{read_samples, wav_err, OTWriter};
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use std::path::{Path, PathBuf};
+
+/// Maximum number of slices in an .ot file.
+pub const MAX_SLICES: usize = 64;
+
+/// A length in sample frames, or in steps (1/16 notes) at the tempo of
+/// the chain.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Length {
+  Frames(u32),
+  Steps(u32),
+}
+
+impl Length {
+  pub fn to_frames(self, sample_rate: u32, tempo: f32) -> std::io::Result<u32> {
+    match self {
+      Length::Frames(n) => Ok(n),
+      Length::Steps(n) if tempo > 0. => Ok((n as f64 * sample_rate as f64 * 15. / tempo as f64).round() as u32),
+      Length::Steps(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "step lengths require a tempo")),
+    }
+  }
+}
+
+/// Parse a length as N (frames), Nst (steps) or Nbar (bars of 16
+/// steps).
+impl std::str::FromStr for Length {
+  type Err = std::io::Error;
+  fn from_str(s: &str) -> std::io::Result<Self> {
+    let s = s.trim().to_lowercase();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let n: u32 = s[..split].parse()
+      .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid length: {}", s)))?;
+    match &s[split..] {
+      "" | "f" => Ok(Length::Frames(n)),
+      "st" => Ok(Length::Steps(n)),
+      "bar" | "bars" => n.checked_mul(16).map(Length::Steps)
+	.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("length too long: {}", s))),
+      unit => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid length unit: {}. valid = [f, st, bar]", unit))),
+    }
+  }
+}
+
+pub struct ChainWriter {
+  input_files: Vec<PathBuf>,
+  output_file: PathBuf,
+  tempo: f32,
+  trim: Option<f32>,
+  fade: f32,
+  pad: Option<Length>,
+  slot: Option<Length>,
+  ot_writer: OTWriter,
+}
+
+impl ChainWriter {
+  pub fn new<P: AsRef<Path>>(output_file: P) -> Self {
+    let output = output_file.as_ref();
+    ChainWriter {
+      input_files: vec![],
+      output_file: output.to_path_buf(),
+      tempo: 120.,
+      trim: None,
+      fade: 0.,
+      pad: None,
+      slot: None,
+      ot_writer: OTWriter::new(output.with_extension("ot")),
+    }
+  }
+  pub fn tempo(mut self, tempo: f32) -> Self {
+    self.tempo = tempo;
+    self
+  }
+  /// Trim leading and trailing silence below THRESHOLD (in dBFS) from
+  /// each input.
+  pub fn trim(mut self, threshold: f32) -> Self {
+    self.trim = Some(threshold);
+    self
+  }
+  /// Apply a linear fade of MS milliseconds to both ends of each slice
+  /// to avoid clicks at slice boundaries.
+  pub fn fade(mut self, ms: f32) -> Self {
+    self.fade = ms.max(0.);
+    self
+  }
+  /// Pad each slice with silence up to the next multiple of LENGTH. A
+  /// length at least as long as every input gives equal-length slices.
+  pub fn pad(mut self, length: Length) -> Self {
+    self.pad = Some(length);
+    self
+  }
+  /// Give every slice exactly LENGTH frames, truncating or padding each
+  /// input, so that slice N always starts at N * LENGTH. Silent inputs
+  /// keep their slot. The trim and loop length of the .ot file are set
+  /// to the exact length of the chain so that beat stretching follows
+  /// the grid. Overrides `pad`.
+  pub fn slot(mut self, length: Length) -> Self {
+    self.slot = Some(length);
+    self
+  }
+  /// Settings for the generated .ot file. The file name, tempo, sample
+  /// rate and slices are set when the chain is processed.
+  pub fn ot_writer(mut self, ot_writer: OTWriter) -> Self {
+    self.ot_writer = ot_writer;
+    self
+  }
+  pub fn input_files(&self) -> &[PathBuf] {
+    &self.input_files
+  }
+
+  /// Add a wav file, or all wav files in a directory (recursively, in
+  /// file name order).
+  pub fn add_files<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+    let path = path.as_ref();
+
+    if path.is_file() {
+      self.input_files.push(path.to_path_buf());
+    } else if path.is_dir() {
+      let mut entries = std::fs::read_dir(path)?
+	.map(|f| f.map(|f| f.path()))
+	.collect::<std::io::Result<Vec<PathBuf>>>()?;
+      entries.sort();
+      for file in entries {
+	if file.is_dir() {
+	  self.add_files(file)?
+	} else if file.extension().map(|e| e.eq_ignore_ascii_case("wav")).unwrap_or(false) {
+	  self.input_files.push(file);
+	}
+      }
+    } else {
+      return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "files not found"))
+    }
+    Ok(())
+  }
+
+  /// Write the chain and its .ot file. Neither file is overwritten if it
+  /// already exists. Inputs which are entirely silent after trimming are
+  /// left out of the chain.
+  pub fn process(&mut self) -> std::io::Result<()> {
+    for f in [self.output_file.clone(), self.output_file.with_extension("ot")] {
+      if f.exists() {
+	return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("file already exists: {}", f.display())))
+      }
+    }
+    if self.input_files.len() > MAX_SLICES {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("too many input files: {} > {}", self.input_files.len(), MAX_SLICES)))
+    }
+
+    let mut spec: Option<WavSpec> = None;
+    let mut slices = vec![];
+    for file in &self.input_files {
+      let mut source = WavReader::open(file).map_err(wav_err)?;
+      let s = source.spec();
+      let s = WavSpec { bits_per_sample: 16, sample_format: SampleFormat::Int, ..s };
+      match spec {
+	None => spec = Some(s),
+	Some(spec) if spec.channels != s.channels || spec.sample_rate != s.sample_rate => {
+	  return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("channels or sample rate of {} do not match the first input", file.display())))
+	},
+	_ => (),
+      }
+      let samples = self.prepare(read_samples(&mut source)?, s)?;
+      if !samples.is_empty() || self.slot.is_some() {
+	slices.push(samples);
+      }
+    }
+    let spec = spec.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no input files"))?;
+
+    let mut writer = WavWriter::create(&self.output_file, spec).map_err(wav_err)?;
+    let channels = spec.channels as u32;
+    let mut offset = 0;
+    self.ot_writer = std::mem::replace(&mut self.ot_writer, OTWriter::new(""))
+      .file_name(self.output_file.with_extension("ot"))
+      .tempo(self.tempo)
+      .sample_rate(spec.sample_rate)
+      .slices(vec![]);
+    for samples in slices {
+      for s in &samples {
+	writer.write_sample(*s).map_err(wav_err)?;
+      }
+      let len = samples.len() as u32 / channels;
+      self.ot_writer.add_slice(offset, offset + len, None);
+      offset += len;
+    }
+    writer.finalize().map_err(wav_err)?;
+
+    if self.slot.is_some() && self.tempo > 0. {
+      let bars = offset as f32 * self.tempo / (spec.sample_rate as f32 * 60. * 4.);
+      self.ot_writer = std::mem::replace(&mut self.ot_writer, OTWriter::new("")).bars(bars);
+    }
+
+    self.ot_writer.write(offset)
+  }
+
+  /// Apply trim, fade and padding to the interleaved SAMPLES of a single
+  /// input.
+  fn prepare(&self, mut samples: Vec<i16>, spec: WavSpec) -> std::io::Result<Vec<i16>> {
+    let channels = spec.channels.max(1) as usize;
+    if let Some(db) = self.trim {
+      let threshold = (10f32.powf(db / 20.) * i16::MAX as f32) as i32;
+      let loud = |frame: &[i16]| frame.iter().any(|s| (*s as i32).abs() > threshold);
+      let frames: Vec<&[i16]> = samples.chunks(channels).collect();
+      match frames.iter().position(|f| loud(f)) {
+	Some(start) => {
+	  let end = frames.iter().rposition(|f| loud(f)).unwrap() + 1;
+	  samples = samples[start * channels..end * channels].to_vec();
+	},
+	None => samples.clear(),
+      }
+    }
+
+    let slot = match self.slot {
+      Some(slot) => Some(slot.to_frames(spec.sample_rate, self.tempo)? as usize),
+      None => None,
+    };
+    if let Some(slot) = slot {
+      samples.truncate(slot * channels);
+    }
+
+    let len = samples.len() / channels;
+    let fade = ((self.fade / 1000. * spec.sample_rate as f32) as usize).min(len / 2);
+    for i in 0..fade {
+      let gain = i as f32 / fade as f32;
+      for c in 0..channels {
+	let head = i * channels + c;
+	let tail = (len - 1 - i) * channels + c;
+	samples[head] = (samples[head] as f32 * gain) as i16;
+	samples[tail] = (samples[tail] as f32 * gain) as i16;
+      }
+    }
+
+    if let Some(slot) = slot {
+      samples.resize(slot * channels, 0);
+    } else if let Some(pad) = self.pad {
+      let step = pad.to_frames(spec.sample_rate, self.tempo)? as usize;
+      if step > 0 && !len.is_multiple_of(step) {
+	samples.resize((len / step + 1) * step * channels, 0);
+      }
+    }
+    Ok(samples)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn prepare() {
+    let spec = WavSpec { channels: 2, sample_rate: 1000, bits_per_sample: 16, sample_format: SampleFormat::Int };
+    let mut samples = vec![0i16; 20];
+    samples.extend([1000i16; 20]);
+    samples.extend([0i16; 10]);
+
+    // -40dBFS ~= 327
+    let chain = ChainWriter::new("chain.wav").trim(-40.);
+    assert_eq!(chain.prepare(samples.clone(), spec).unwrap(), vec![1000i16; 20]);
+    assert!(chain.prepare(vec![10; 40], spec).unwrap().is_empty());
+
+    let chain = ChainWriter::new("chain.wav").trim(-40.).fade(2.);
+    let faded = chain.prepare(samples.clone(), spec).unwrap();
+    assert_eq!(&faded[..6], &[0, 0, 500, 500, 1000, 1000]);
+    assert_eq!(&faded[14..], &[1000, 1000, 500, 500, 0, 0]);
+
+    // 4 steps at 150bpm and 1000Hz = 400 frames
+    let chain = ChainWriter::new("chain.wav").tempo(150.).pad(Length::Steps(4));
+    assert_eq!(chain.prepare(samples.clone(), spec).unwrap().len(), 800);
+    let chain = ChainWriter::new("chain.wav").trim(-40.).pad(Length::Frames(8));
+    assert_eq!(chain.prepare(samples.clone(), spec).unwrap().len(), 32);
+
+    let chain = ChainWriter::new("chain.wav").trim(-40.).fade(2.).slot(Length::Frames(6)).pad(Length::Frames(100));
+    assert_eq!(chain.prepare(samples.clone(), spec).unwrap(), vec![0, 0, 500, 500, 1000, 1000, 1000, 1000, 500, 500, 0, 0]);
+    let chain = ChainWriter::new("chain.wav").tempo(150.).slot(Length::Steps(1));
+    assert_eq!(chain.prepare(samples, spec).unwrap().len(), 200);
+    assert_eq!(chain.prepare(vec![], spec).unwrap(), vec![0; 200]);
+
+    assert_eq!("2bar".parse::<Length>().unwrap(), Length::Steps(32));
+    assert!("300000000bar".parse::<Length>().is_err());
+  }
+}
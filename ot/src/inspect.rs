@@ -0,0 +1,149 @@
+//! inspect --- human-readable view of .ot files and differences between
+//! them
+use crate::{OTData, Slice};
+
+/// Every field of an .ot file in real units. Frame positions are shown
+/// as time at the given sample rate, since .ot files don't store it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Inspector {
+  data: OTData,
+  sample_rate: u32,
+}
+
+/// A field which differs between two .ot files. A value of None means
+/// the field (a slice) is not present in that file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Difference {
+  pub field: String,
+  pub a: Option<String>,
+  pub b: Option<String>,
+}
+
+impl Inspector {
+  pub fn new(data: OTData, sample_rate: u32) -> Self {
+    Inspector { data, sample_rate }
+  }
+
+  /// Format a position of FRAMES as minutes, seconds and milliseconds.
+  pub fn time(&self, frames: u32) -> String {
+    let ms = frames as u64 * 1000 / self.sample_rate.max(1) as u64;
+    format!("{}:{:02}.{:03}", ms / 60000, ms / 1000 % 60, ms % 1000)
+  }
+
+  fn position(&self, frames: u32) -> String {
+    format!("{} ({})", self.time(frames), frames)
+  }
+
+  /// Name and value of each field, except for slices.
+  pub fn fields(&self) -> Vec<(&'static str, String)> {
+    let d = &self.data;
+    let invalid = |e: std::io::Error| format!("invalid ({})", e);
+    let checksum = if d.checksum() == d.calc_checksum() {
+      format!("{} (ok)", d.checksum())
+    } else {
+      format!("{} (expected {})", d.checksum(), d.calc_checksum())
+    };
+    vec![
+      ("tempo", format!("{:.2} BPM", d.tempo())),
+      ("trim_len", format!("{:.2} bars", d.trim_len() as f32 / 100.)),
+      ("loop_len", format!("{:.2} bars", d.loop_len() as f32 / 100.)),
+      ("stretch", d.stretch_type().map(|s| s.to_string()).unwrap_or_else(invalid)),
+      ("loop", d.loop_type().map(|s| s.to_string()).unwrap_or_else(invalid)),
+      ("gain", format!("{:+.1} dB", d.gain() as f32 / 2.)),
+      ("quantize", d.trig_quant_type().map(|s| s.to_string()).unwrap_or_else(invalid)),
+      ("trim_start", self.position(d.trim_start())),
+      ("trim_end", self.position(d.trim_end())),
+      ("loop_point", self.position(d.loop_point())),
+      ("slice_count", d.slice_count().to_string()),
+      ("checksum", checksum),
+    ]
+  }
+
+  /// The stored values of the fields, in the order of `fields`.
+  fn raw_fields(&self) -> [u64; 12] {
+    let d = &self.data;
+    [d.tempo as u64, d.trim_len as u64, d.loop_len as u64, d.stretch as u64, d.r#loop as u64, d.gain as u64,
+     d.quantize as u64, d.trim_start as u64, d.trim_end as u64, d.loop_point as u64, d.slice_count as u64, d.checksum as u64]
+  }
+
+  /// Start, end, length and loop point of SLICE.
+  pub fn slice(&self, slice: &Slice) -> String {
+    let lp = match slice.loop_start() {
+      Some(lp) => self.position(lp),
+      None => "-".to_string(),
+    };
+    format!("{} - {}, length {}, loop {}",
+	    self.position(slice.start()), self.position(slice.end()),
+	    self.position(slice.end().saturating_sub(slice.start())), lp)
+  }
+
+  /// Field-by-field and slice-by-slice differences from OTHER. The
+  /// stored values are compared, so files with the same contents don't
+  /// differ whatever the sample rates they are shown at.
+  pub fn diff(&self, other: &Inspector) -> Vec<Difference> {
+    let changed = self.raw_fields().into_iter().zip(other.raw_fields()).map(|(a, b)| a != b);
+    let mut diff: Vec<Difference> = self.fields().into_iter()
+      .zip(other.fields())
+      .zip(changed)
+      .filter(|(_, changed)| *changed)
+      .map(|((a, b), _)| Difference { field: a.0.to_string(), a: Some(a.1), b: Some(b.1) })
+      .collect();
+    let (a, b) = (self.data.slices(), other.data.slices());
+    for i in 0..a.len().max(b.len()) {
+      if a.get(i) != b.get(i) {
+	diff.push(Difference {
+	  field: format!("slice {}", i + 1),
+	  a: a.get(i).map(|s| self.slice(s)),
+	  b: b.get(i).map(|s| other.slice(s)),
+	});
+      }
+    }
+    diff
+  }
+}
+
+impl std::fmt::Display for Inspector {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for (name, value) in self.fields() {
+      writeln!(f, "{:<12} {}", name, value)?;
+    }
+    writeln!(f, "slices:")?;
+    for (i, slice) in self.data.slices().iter().enumerate() {
+      writeln!(f, "{:>4}  {}", i + 1, self.slice(slice))?;
+    }
+    Ok(())
+  }
+}
+
+impl std::fmt::Display for Difference {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let none = "(none)".to_string();
+    write!(f, "{}: {} -> {}", self.field, self.a.as_ref().unwrap_or(&none), self.b.as_ref().unwrap_or(&none))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{OTWriter, LoopType};
+  #[test]
+  fn diff() {
+    let mut a = OTWriter::new("a.ot").sample_rate(44100).tempo(120.);
+    a.add_slice(0, 44100, None);
+    a.update_data();
+    let mut b = OTWriter::new("b.ot").sample_rate(44100).tempo(120.).loop_type(LoopType::Loop);
+    b.add_slice(0, 22050, Some(100));
+    b.add_slice(22050, 44100, None);
+    b.update_data();
+
+    let a = Inspector::new(a.data, 44100);
+    let b = Inspector::new(b.data, 44100);
+    assert_eq!(a.time(66150), "0:01.500");
+    assert!(a.diff(&a).is_empty());
+    // only the way positions are shown depends on the sample rate
+    assert!(a.diff(&Inspector::new(a.data, 48000)).is_empty());
+    let diff: Vec<String> = a.diff(&b).iter().map(|d| d.field.clone()).collect();
+    assert_eq!(diff, ["loop", "slice_count", "checksum", "slice 1", "slice 2"]);
+    assert_eq!(a.diff(&b)[4].a, None);
+  }
+}
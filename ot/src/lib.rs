@@ -3,9 +3,16 @@
 //! Based on [https://github.com/KaiDrange/OctaChainer/blob/master/otwriter.h]
 use std::io::Write;
 
+pub mod chain;
+pub mod inspect;
+pub mod sampler;
+pub mod op1;
+
 pub const FILE_SIZE: usize = 832;
 pub const HEADER_BYTES: [u8; 16] = [0x46, 0x4F, 0x52, 0x4D, 0x00, 0x00, 0x00, 0x00, 0x44, 0x50, 0x53, 0x31, 0x53, 0x4D, 0x50, 0x41];
 pub const UNKNOWN_BYTES: [u8; 7] = [0x00,0x00,0x00,0x00,0x00,0x02,0x00];
+/// Gain range in half-dB steps, -24 to +24 dB.
+pub const GAIN_RANGE: std::ops::RangeInclusive<i32> = -48..=48;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum LoopType {
@@ -36,6 +43,18 @@ impl std::fmt::Display for LoopType {
   }
 }
 
+impl std::str::FromStr for LoopType {
+  type Err = std::io::Error;
+  fn from_str(s: &str) -> std::io::Result<Self> {
+    match s.to_lowercase().as_str() {
+      "off" => Ok(LoopType::Off),
+      "loop" => Ok(LoopType::Loop),
+      "pingpong" => Ok(LoopType::PingPong),
+      _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid loop type. valid = [off, loop, pingpong]"))
+    }
+  }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum StretchType {
   Off = 0,
@@ -65,6 +84,18 @@ impl std::fmt::Display for StretchType {
   }
 }
 
+impl std::str::FromStr for StretchType {
+  type Err = std::io::Error;
+  fn from_str(s: &str) -> std::io::Result<Self> {
+    match s.to_lowercase().as_str() {
+      "off" => Ok(StretchType::Off),
+      "normal" => Ok(StretchType::Normal),
+      "beat" => Ok(StretchType::Beat),
+      _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid stretch type. valid = [off, normal, beat]"))
+    }
+  }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TrigQuantType {
   Direct = 0xFF,
@@ -139,6 +170,17 @@ impl std::fmt::Display for TrigQuantType {
   }
 }
 
+impl std::str::FromStr for TrigQuantType {
+  type Err = std::io::Error;
+  fn from_str(s: &str) -> std::io::Result<Self> {
+    let s = s.to_lowercase();
+    (0..=16).chain([0xFF])
+      .filter_map(|n| TrigQuantType::try_from(n).ok())
+      .find(|q| q.to_string() == s)
+      .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid trig quantization. valid = [direct, pattern, s1..s256]"))
+  }
+}
+
 fn push_u32(vector: &mut Vec<u8>, num: u32) {
   let array = num.to_le_bytes();
   for i in 0..4 {
@@ -158,9 +200,34 @@ fn pop_u32(array: &mut [u8]) -> u32 {
 }
 
 fn pop_u16(array: &mut [u8]) -> u16 {
+  array.reverse();
   u16::from_le_bytes(array.try_into().unwrap())
 }
 
+pub(crate) fn wav_err(e: hound::Error) -> std::io::Error {
+  match e {
+    hound::Error::IoError(e) => e,
+    e => std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()),
+  }
+}
+
+/// Read all samples from a WAV file as 16-bit integers, converting
+/// from other bit depths and float samples as needed.
+pub(crate) fn read_samples<R: std::io::Read>(reader: &mut hound::WavReader<R>) -> std::io::Result<Vec<i16>> {
+  let spec = reader.spec();
+  match (spec.sample_format, spec.bits_per_sample) {
+    (hound::SampleFormat::Float, _) => reader.samples::<f32>()
+      .map(|s| s.map(|s| (s.clamp(-1., 1.) * i16::MAX as f32) as i16))
+      .collect::<Result<_, _>>(),
+    (hound::SampleFormat::Int, bits) if bits <= 16 => reader.samples::<i16>()
+      .map(|s| s.map(|s| s << (16 - bits)))
+      .collect::<Result<_, _>>(),
+    (hound::SampleFormat::Int, bits) => reader.samples::<i32>()
+      .map(|s| s.map(|s| (s >> (bits - 16)) as i16))
+      .collect::<Result<_, _>>(),
+  }.map_err(wav_err)
+}
+
 #[derive(Default, Copy, Clone, Debug, PartialEq)]
 pub struct Slice {
   start_point: u32,
@@ -182,10 +249,32 @@ impl Slice {
     self
   }
 
+  pub fn start(&self) -> u32 {
+    self.start_point
+  }
+
+  pub fn end(&self) -> u32 {
+    self.end_point
+  }
+
+  /// Loop point of the slice, or None if the slice has no loop point
+  /// inside of its bounds.
+  pub fn loop_start(&self) -> Option<u32> {
+    if self.loop_point >= self.start_point && self.loop_point < self.end_point {
+      Some(self.loop_point)
+    } else {
+      None
+    }
+  }
+
   pub fn len(&self) -> u32 {
     self.end_point - self.start_point
   }
 
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
   pub fn to_vec(&self) -> Vec<u8> {
     self.into()
   }
@@ -270,6 +359,102 @@ impl OTData {
   pub fn to_vec(self) -> Vec<u8> {
     self.into()
   }
+  /// Slices in use, as indicated by the slice count.
+  pub fn slices(&self) -> &[Slice] {
+    &self.slices[..(self.slice_count as usize).min(64)]
+  }
+  /// Decode the contents of an .ot file, returning an error instead of
+  /// panicking on malformed data.
+  pub fn decode(bytes: &[u8]) -> std::io::Result<Self> {
+    if bytes.len() != FILE_SIZE {
+      Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid file size: {} != {}", bytes.len(), FILE_SIZE)))
+    } else if bytes[0..16] != HEADER_BYTES || bytes[16..23] != UNKNOWN_BYTES {
+      Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid header"))
+    } else {
+      Ok(OTData::from(bytes.to_vec()))
+    }
+  }
+  /// Tempo in BPM.
+  pub fn tempo(&self) -> f32 {
+    self.tempo as f32 / 24.
+  }
+  /// Set the tempo in BPM. Trim and loop lengths are scaled to match.
+  pub fn set_tempo(&mut self, tempo: f32) {
+    let old = self.tempo;
+    self.tempo = (tempo * 24.) as u32;
+    if old != 0 {
+      self.trim_len = (self.trim_len as u64 * self.tempo as u64 / old as u64) as u32;
+      self.loop_len = (self.loop_len as u64 * self.tempo as u64 / old as u64) as u32;
+    }
+  }
+  /// Trim length in hundredths of a bar.
+  pub fn trim_len(&self) -> u32 {
+    self.trim_len
+  }
+  /// Loop length in hundredths of a bar.
+  pub fn loop_len(&self) -> u32 {
+    self.loop_len
+  }
+  pub fn stretch_type(&self) -> std::io::Result<StretchType> {
+    self.stretch.try_into()
+  }
+  pub fn set_stretch_type(&mut self, stretch_type: StretchType) {
+    self.stretch = stretch_type as u32;
+  }
+  pub fn loop_type(&self) -> std::io::Result<LoopType> {
+    self.r#loop.try_into()
+  }
+  pub fn set_loop_type(&mut self, loop_type: LoopType) {
+    self.r#loop = loop_type as u32;
+  }
+  /// Gain in half-dB steps, without the +48 bias used in the file.
+  pub fn gain(&self) -> i32 {
+    self.gain as i32 - 48
+  }
+  /// Set the gain in half-dB steps, within `GAIN_RANGE`.
+  pub fn set_gain(&mut self, gain: i32) -> std::io::Result<()> {
+    if !GAIN_RANGE.contains(&gain) {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("gain out of range: {}. valid = [-48..48]", gain)))
+    }
+    self.gain = (gain + 48) as u16;
+    Ok(())
+  }
+  pub fn trig_quant_type(&self) -> std::io::Result<TrigQuantType> {
+    self.quantize.try_into()
+  }
+  pub fn set_trig_quant_type(&mut self, trig_quant_type: TrigQuantType) {
+    self.quantize = trig_quant_type as u8;
+  }
+  pub fn trim_start(&self) -> u32 {
+    self.trim_start
+  }
+  pub fn trim_end(&self) -> u32 {
+    self.trim_end
+  }
+  pub fn loop_point(&self) -> u32 {
+    self.loop_point
+  }
+  pub fn slice_count(&self) -> u32 {
+    self.slice_count
+  }
+  pub fn checksum(&self) -> u16 {
+    self.checksum
+  }
+  /// Sum of all bytes except for the header and checksum.
+  pub fn calc_checksum(&self) -> u16 {
+    let data: Vec<u8> = (*self).into();
+    // first 16, last 2
+    data[16..(data.len()-2)].iter().fold(0u16, |val, b| val.wrapping_add(*b as u16))
+  }
+  pub fn update_checksum(&mut self) {
+    self.checksum = self.calc_checksum();
+  }
+}
+
+impl Default for OTData {
+  fn default() -> Self {
+    OTData::new()
+  }
 }
 
 impl From<Vec<u8>> for OTData {
@@ -330,10 +515,11 @@ pub struct OTWriter {
   file_name: std::path::PathBuf,
   sample_rate: u32,
   tempo: f32,
-  gain: u16,
+  gain: i32,
   loop_type: LoopType,
   stretch_type: StretchType,
   trig_quant_type: TrigQuantType,
+  bars: Option<f32>,
   slices: Vec<Slice>,
   pub data: OTData,
 }
@@ -349,6 +535,7 @@ impl OTWriter {
       loop_type: LoopType::Off,
       stretch_type: StretchType::Normal,
       trig_quant_type: TrigQuantType::Direct,
+      bars: None,
       slices: Vec::with_capacity(64),
       data: OTData::new(),
     }
@@ -369,20 +556,32 @@ impl OTWriter {
     self.tempo = tempo;
     self
   }
+  /// Gain in half-dB steps above 0 dB. Use `gain_db` for cuts.
   pub fn gain(mut self, gain: u16) -> Self {
-    self.gain = gain;
+    self.gain = gain as i32;
+    self
+  }
+  /// Gain in dB, rounded to half-dB steps and clamped to -24..24 dB.
+  pub fn gain_db(mut self, db: f32) -> Self {
+    self.gain = ((db * 2.).round() as i32).clamp(*GAIN_RANGE.start(), *GAIN_RANGE.end());
     self
   }
   pub fn loop_type(mut self, loop_type: LoopType) -> Self {
-    self.loop_type = loop_type.into();
+    self.loop_type = loop_type;
     self
   }
   pub fn stretch_type(mut self, stretch_type: StretchType) -> Self {
-    self.stretch_type = stretch_type.into();
+    self.stretch_type = stretch_type;
     self
   }
   pub fn trig_quant_type(mut self, trig_quant_type: TrigQuantType) -> Self {
-    self.trig_quant_type = trig_quant_type.into();
+    self.trig_quant_type = trig_quant_type;
+    self
+  }
+  /// Set the trim and loop length in bars, instead of deriving it from
+  /// the tempo and sample count.
+  pub fn bars(mut self, bars: f32) -> Self {
+    self.bars = Some(bars);
     self
   }
   pub fn slices(mut self, slices: Vec<Slice>) -> Self {
@@ -395,7 +594,8 @@ impl OTWriter {
   }
 
   pub fn reset<P: AsRef<std::path::Path>>(self, file_name: P) -> Self {
-    self.file_name(file_name)
+    let writer = OTWriter { bars: None, ..self };
+    writer.file_name(file_name)
       .total_sample_count(0)
       .sample_rate(0)
       .tempo(0.)
@@ -414,12 +614,33 @@ impl OTWriter {
       .data(data)
       .sample_rate(sample_rate)
       .tempo(data.tempo as f32/24.)
-      .gain(data.gain.to_be() - 48)
+      .gain_db(data.gain() as f32 / 2.)
       .loop_type(data.r#loop.try_into().unwrap())
       .stretch_type(data.stretch.try_into().unwrap())
       .trig_quant_type(data.quantize.try_into().unwrap())
   }
 
+  /// Load an .ot file, including its slices and sample count. Unlike
+  /// `from_file`, malformed files are returned as errors.
+  pub fn open<P: AsRef<std::path::Path>>(file: P, sample_rate: u32) -> std::io::Result<Self> {
+    let data = OTData::decode(&std::fs::read(&file)?)?;
+    Ok(OTWriter::new(file)
+       .data(data)
+       .sample_rate(sample_rate)
+       .total_sample_count(data.trim_end)
+       .tempo(data.tempo())
+       .gain_db(data.gain() as f32 / 2.)
+       .loop_type(data.loop_type()?)
+       .stretch_type(data.stretch_type()?)
+       .trig_quant_type(data.trig_quant_type()?)
+       .slices(data.slices().to_vec()))
+  }
+
+  /// Write the current data buffer, replacing the file if it exists.
+  pub fn overwrite(&self) -> std::io::Result<()> {
+    std::fs::write(&self.file_name, self.data.to_vec())
+  }
+
   pub fn write(&mut self, total_samples: u32) -> std::io::Result<()> {
     self.total_sample_count = total_samples;
     self.update_data();
@@ -436,7 +657,7 @@ impl OTWriter {
     let slice = Slice {
       start_point,
       end_point,
-      loop_point: if let Some(n) = loop_point { n } else { 0xFF }
+      loop_point: loop_point.unwrap_or(0xFFFFFFFF)
     };
 
     self.slices.push(slice);
@@ -447,7 +668,11 @@ impl OTWriter {
     self.data.tempo = (self.tempo * 6. * 4.) as u32;
 
     // 25 * ((tempo*s_count)/(s_rate*60) + 0.5)
-    let bars: f32 = ((self.tempo as f32 * self.total_sample_count as f32) / (self.sample_rate * 60) as f32 + 0.5) * 25.;
+    let bars: f32 = match self.bars {
+      // stored in hundredths of a bar
+      Some(bars) => (bars * 100.).round(),
+      None => ((self.tempo * self.total_sample_count as f32) / (self.sample_rate * 60) as f32 + 0.5) * 25.,
+    };
 
     self.data.trim_len = bars as u32;
     self.data.loop_len = bars as u32;
@@ -467,13 +692,7 @@ impl OTWriter {
   }
 
   pub fn set_checksum(&mut self) {
-    let mut val: u16 = 0;
-    let data: Vec<u8> = self.data.into();
-    // sum all bytes except for header and checksum (first 16, last 2)
-    for i in 16..(data.len()-2) {
-      val += data[i] as u16;
-    }
-    self.data.checksum = val;
+    self.data.update_checksum();
   }
 }
 
@@ -536,4 +755,37 @@ mod tests {
       dbg!(data);
       dbg!(writer.data);
     }
+    #[test]
+    fn field_bytes() {
+      // u16 fields are big-endian like the rest of the file: gain 12
+      // is stored as 60 = 00 3C, which decoded little-endian read 0x3C00
+      let mut bytes = OTData::new().to_vec();
+      bytes[43..45].copy_from_slice(&[0x00, 0x3C]);
+      bytes[830..832].copy_from_slice(&[0x12, 0x34]);
+      let data = OTData::from(bytes.clone());
+      assert_eq!((data.gain, data.checksum), (60, 0x1234));
+      assert_eq!(data.to_vec(), bytes);
+
+      // slices without a loop point are marked with FF FF FF FF. 0xFF
+      // was stored as 00 00 00 FF, a loop point at sample 255.
+      let mut writer = OTWriter::new("").sample_rate(44100).tempo(120.);
+      writer.add_slice(0, 1000, None);
+      writer.update_data();
+      assert_eq!(writer.data.to_vec()[66..70], [0xFF; 4]);
+
+      // gains are range checked, negative ones included
+      let mut data = OTData::new();
+      data.set_gain(-48).unwrap();
+      assert_eq!((data.gain, data.gain()), (0, -48));
+      assert!(data.set_gain(49).is_err());
+      writer = writer.gain_db(f32::MAX);
+      writer.update_data();
+      assert_eq!(writer.data.gain(), 48);
+      writer = writer.gain_db(-3.5);
+      writer.update_data();
+      assert_eq!(writer.data.gain(), -7);
+      writer = writer.gain(12);
+      writer.update_data();
+      assert_eq!(writer.data.gain(), 12);
+    }
 }
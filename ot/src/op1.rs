@@ -0,0 +1,347 @@
+//! op1 --- Teenage Engineering OP-1/OP-Z drum kits
+//!
+//! A drum kit is a mono 16-bit 44.1kHz AIFF file. Start and end points
+//! for each of the 24 keys are stored as JSON in an APPL chunk with
+//! the 'op-1' signature.
+use crate::{OTData, Slice};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Number of keys in a drum kit.
+pub const SLOTS: usize = 24;
+pub const SAMPLE_RATE: u32 = 44100;
+/// Kit positions are stored as sample frames multiplied by this value.
+pub const POSITION_SCALE: u64 = 4058;
+pub const APPL_SIGNATURE: [u8; 4] = *b"op-1";
+
+/// The JSON metadata stored in the APPL chunk.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KitData {
+  pub drum_version: u32,
+  pub r#type: String,
+  pub name: String,
+  pub octave: i32,
+  pub pitch: Vec<i32>,
+  pub start: Vec<u64>,
+  pub end: Vec<u64>,
+  pub playmode: Vec<u32>,
+  pub reverse: Vec<u32>,
+  pub volume: Vec<u32>,
+  pub dyna_env: Vec<u32>,
+  pub fx_active: bool,
+  pub fx_type: String,
+  pub fx_params: Vec<u32>,
+  pub lfo_active: bool,
+  pub lfo_type: String,
+  pub lfo_params: Vec<u32>,
+}
+
+impl Default for KitData {
+  fn default() -> Self {
+    KitData {
+      drum_version: 2,
+      r#type: "drum".to_string(),
+      name: "user".to_string(),
+      octave: 0,
+      pitch: vec![0; SLOTS],
+      start: vec![0; SLOTS],
+      end: vec![0; SLOTS],
+      playmode: vec![8192; SLOTS],
+      reverse: vec![8192; SLOTS],
+      volume: vec![8192; SLOTS],
+      dyna_env: vec![0, 8192, 0, 8192, 0, 0, 0, 0],
+      fx_active: false,
+      fx_type: "delay".to_string(),
+      fx_params: vec![8000; 8],
+      lfo_active: false,
+      lfo_type: "tremolo".to_string(),
+      lfo_params: vec![16000, 16000, 16000, 16000, 0, 0, 0, 0],
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DrumKit {
+  name: String,
+  samples: Vec<i16>,
+  slices: Vec<Slice>,
+}
+
+impl DrumKit {
+  pub fn new(name: &str) -> Self {
+    DrumKit {
+      name: name.to_string(),
+      samples: vec![],
+      slices: Vec::with_capacity(SLOTS),
+    }
+  }
+  pub fn name(mut self, name: &str) -> Self {
+    self.name = name.to_string();
+    self
+  }
+  /// Mono 16-bit sample data at SAMPLE_RATE.
+  pub fn samples(mut self, samples: Vec<i16>) -> Self {
+    self.samples = samples;
+    self
+  }
+  pub fn slices(mut self, slices: Vec<Slice>) -> Self {
+    self.slices = slices;
+    self
+  }
+  pub fn get_slices(&self) -> &[Slice] {
+    &self.slices
+  }
+  pub fn get_samples(&self) -> &[i16] {
+    &self.samples
+  }
+
+  /// Load the audio of a kit from a WAV file. Multi-channel files are
+  /// mixed down to mono.
+  pub fn from_wav<P: AsRef<Path>>(wav: P, slices: Vec<Slice>) -> std::io::Result<Self> {
+    let wav = wav.as_ref();
+    let mut reader = hound::WavReader::open(wav).map_err(crate::wav_err)?;
+    let spec = reader.spec();
+    if spec.sample_rate != SAMPLE_RATE {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("drum kits must be {} Hz: {}", SAMPLE_RATE, wav.display())))
+    }
+    let samples = crate::read_samples(&mut reader)?;
+    let channels = spec.channels.max(1) as usize;
+    let mono = samples.chunks(channels)
+      .map(|frame| (frame.iter().map(|s| *s as i32).sum::<i32>() / channels as i32) as i16)
+      .collect();
+    let name = wav.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    Ok(DrumKit::new(&name).samples(mono).slices(slices))
+  }
+
+  /// Load a kit from a WAV file and the slices of its matching .ot file.
+  pub fn from_ot<W: AsRef<Path>, O: AsRef<Path>>(wav: W, ot: O) -> std::io::Result<Self> {
+    let data = OTData::decode(&std::fs::read(ot)?)?;
+    DrumKit::from_wav(wav, data.slices().to_vec())
+  }
+
+  pub fn kit_data(&self) -> std::io::Result<KitData> {
+    if self.slices.len() > SLOTS {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("too many slices for a drum kit: {} > {}", self.slices.len(), SLOTS)))
+    }
+    let mut data = KitData { name: self.name.chars().take(32).collect(), ..Default::default() };
+    let frames = self.samples.len() as u64;
+    for i in 0..SLOTS {
+      // unused keys play back the last sample frame
+      let (start, end) = match self.slices.get(i) {
+	Some(s) => (s.start() as u64, s.end() as u64),
+	None => (frames, frames),
+      };
+      data.start[i] = start.min(frames) * POSITION_SCALE;
+      data.end[i] = end.min(frames) * POSITION_SCALE;
+    }
+    Ok(data)
+  }
+
+  /// Write the kit as an AIFF file. Existing files are not overwritten.
+  pub fn write<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let mut appl = APPL_SIGNATURE.to_vec();
+    serde_json::to_writer(&mut appl, &self.kit_data()?)?;
+    appl.push(b'\n');
+
+    let mut comm = vec![];
+    comm.extend(1i16.to_be_bytes());
+    comm.extend((self.samples.len() as u32).to_be_bytes());
+    comm.extend(16i16.to_be_bytes());
+    comm.extend(extended_from_u32(SAMPLE_RATE));
+
+    let mut ssnd = vec![0u8; 8];
+    for s in &self.samples {
+      ssnd.extend(s.to_be_bytes());
+    }
+
+    let mut form = b"AIFF".to_vec();
+    push_chunk(&mut form, b"COMM", &comm);
+    push_chunk(&mut form, b"APPL", &appl);
+    push_chunk(&mut form, b"SSND", &ssnd);
+
+    if !path.exists() {
+      let mut file = std::fs::File::create(path)?;
+      file.write_all(b"FORM")?;
+      file.write_all(&(form.len() as u32).to_be_bytes())?;
+      file.write_all(&form)
+    } else {
+      Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("file already exists: {}", path.display())))
+    }
+  }
+
+  /// Read a drum kit AIFF or AIFC file, uncompressed or with
+  /// little-endian (sowt) samples. Multi-channel files are mixed down
+  /// to mono, and keys with an empty range are skipped.
+  pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+    let mut bytes = vec![];
+    std::fs::File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.len() < 12 || &bytes[0..4] != b"FORM" || (&bytes[8..12] != b"AIFF" && &bytes[8..12] != b"AIFC") {
+      return Err(invalid("not an AIFF file"))
+    }
+    let mut kit: Option<KitData> = None;
+    let aifc = &bytes[8..12] == b"AIFC";
+    let mut channels = 1;
+    let mut bits = 16;
+    let mut big_endian = true;
+    let mut samples = vec![];
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+      let id = &bytes[pos..pos+4];
+      let len = u32::from_be_bytes(bytes[pos+4..pos+8].try_into().unwrap()) as usize;
+      let body = bytes.get(pos+8..pos+8+len).ok_or_else(|| invalid("truncated chunk"))?;
+      match id {
+	b"COMM" if len >= 8 => {
+	  channels = i16::from_be_bytes([body[0], body[1]]).max(1) as usize;
+	  bits = i16::from_be_bytes([body[6], body[7]]);
+	  if aifc {
+	    big_endian = match body.get(18..22) {
+	      Some(b"NONE") | Some(b"twos") => true,
+	      Some(b"sowt") => false,
+	      _ => return Err(invalid("unsupported AIFC compression")),
+	    };
+	  }
+	},
+	b"APPL" if body.starts_with(&APPL_SIGNATURE) => {
+	  let json = &body[4..];
+	  let end = json.iter().rposition(|b| *b == b'}').map(|n| n + 1).unwrap_or(json.len());
+	  kit = Some(serde_json::from_slice(&json[..end])?);
+	},
+	b"SSND" if len >= 8 => {
+	  if bits != 16 {
+	    return Err(invalid("only 16-bit drum kits are supported"))
+	  }
+	  // the samples start OFFSET bytes into the sound data
+	  let offset = u32::from_be_bytes(body[0..4].try_into().unwrap()) as usize;
+	  let data = body.get(8 + offset..).ok_or_else(|| invalid("truncated sound data"))?;
+	  // multi-channel kits are mixed down to mono, as in from_wav
+	  samples = data.chunks_exact(2 * channels)
+	    .map(|frame| {
+	      let sum: i32 = frame.chunks_exact(2)
+		.map(|s| match big_endian {
+		  true => i16::from_be_bytes([s[0], s[1]]),
+		  false => i16::from_le_bytes([s[0], s[1]]),
+		} as i32)
+		.sum();
+	      (sum / channels as i32) as i16
+	    })
+	    .collect();
+	},
+	_ => (),
+      }
+      // chunks are padded to an even length
+      pos += 8 + len + (len & 1);
+    }
+    let kit = kit.ok_or_else(|| invalid("missing op-1 APPL chunk"))?;
+    let slices = kit.start.iter().zip(kit.end.iter())
+      .filter(|(start, end)| end > start)
+      .map(|(start, end)| Slice::new((start / POSITION_SCALE) as u32, (end / POSITION_SCALE) as u32))
+      .collect();
+    Ok(DrumKit::new(&kit.name).samples(samples).slices(slices))
+  }
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn push_chunk(form: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+  form.extend(id);
+  form.extend((body.len() as u32).to_be_bytes());
+  form.extend(body);
+  if body.len() & 1 == 1 {
+    form.push(0);
+  }
+}
+
+/// Encode N as an 80-bit IEEE 754 extended precision float, as used
+/// for the sample rate in AIFF COMM chunks.
+fn extended_from_u32(n: u32) -> [u8; 10] {
+  let mut bytes = [0u8; 10];
+  if n != 0 {
+    let shift = (n as u64).leading_zeros();
+    let exponent = (16383 + 63 - shift) as u16;
+    bytes[0..2].copy_from_slice(&exponent.to_be_bytes());
+    bytes[2..10].copy_from_slice(&((n as u64) << shift).to_be_bytes());
+  }
+  bytes
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn round_trip() {
+    let path = std::path::Path::new("test_kit.aif");
+    if path.exists() {
+      std::fs::remove_file(path).unwrap();
+    }
+    assert_eq!(extended_from_u32(44100), [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]);
+
+    let kit = DrumKit::new("test")
+      .samples((0..1000).map(|n| n as i16).collect())
+      .slices(vec![Slice::new(0, 250), Slice::new(250, 1000)]);
+    kit.write(path).unwrap();
+    assert!(kit.write(path).is_err());
+
+    let other = DrumKit::read(path).unwrap();
+    assert_eq!(other, kit);
+    std::fs::remove_file(path).unwrap();
+
+    // AIFC with little-endian samples, and an unsupported compression
+    for (compression, ok) in [(b"sowt", true), (b"ima4", false)] {
+      let mut comm = 1i16.to_be_bytes().to_vec();
+      comm.extend(1000u32.to_be_bytes());
+      comm.extend(16i16.to_be_bytes());
+      comm.extend(extended_from_u32(SAMPLE_RATE));
+      comm.extend(compression);
+      comm.extend([0, 0]);
+      let mut appl = APPL_SIGNATURE.to_vec();
+      serde_json::to_writer(&mut appl, &kit.kit_data().unwrap()).unwrap();
+      let mut ssnd = vec![0u8; 8];
+      ssnd.extend((0..1000).flat_map(|n| (n as i16).to_le_bytes()));
+      let mut form = b"AIFC".to_vec();
+      push_chunk(&mut form, b"COMM", &comm);
+      push_chunk(&mut form, b"APPL", &appl);
+      push_chunk(&mut form, b"SSND", &ssnd);
+      let mut bytes = b"FORM".to_vec();
+      bytes.extend((form.len() as u32).to_be_bytes());
+      bytes.extend(form);
+      std::fs::write(path, bytes).unwrap();
+      match ok {
+	true => assert_eq!(DrumKit::read(path).unwrap(), kit),
+	false => assert!(DrumKit::read(path).is_err()),
+      }
+    }
+    std::fs::remove_file(path).unwrap();
+
+    // sound data after an offset, in stereo
+    let mut comm = 2i16.to_be_bytes().to_vec();
+    comm.extend(4u32.to_be_bytes());
+    comm.extend(16i16.to_be_bytes());
+    comm.extend(extended_from_u32(SAMPLE_RATE));
+    let mut appl = APPL_SIGNATURE.to_vec();
+    serde_json::to_writer(&mut appl, &kit.kit_data().unwrap()).unwrap();
+    let mut ssnd = 4u32.to_be_bytes().to_vec();
+    ssnd.extend([0u8; 4]);
+    ssnd.extend([0xFF; 4]);
+    ssnd.extend([10i16, 20, -10, -30, 0, 0, 7, 7].iter().flat_map(|n| n.to_be_bytes()));
+    let mut form = b"AIFF".to_vec();
+    push_chunk(&mut form, b"COMM", &comm);
+    push_chunk(&mut form, b"APPL", &appl);
+    push_chunk(&mut form, b"SSND", &ssnd);
+    let mut bytes = b"FORM".to_vec();
+    bytes.extend((form.len() as u32).to_be_bytes());
+    bytes.extend(form);
+    std::fs::write(path, bytes).unwrap();
+    assert_eq!(DrumKit::read(path).unwrap().get_samples(), [15, -20, 0, 7]);
+    std::fs::remove_file(path).unwrap();
+
+    assert!(DrumKit::from_ot("missing.wav", std::env::temp_dir().join("ot_missing.ot")).is_err());
+
+    let too_many = DrumKit::new("test").slices(vec![Slice::new(0, 1); SLOTS + 1]);
+    assert!(too_many.kit_data().is_err());
+  }
+}
@@ -0,0 +1,187 @@
+//! sampler --- export slices as software sampler instruments
+//!
+//! Supports SFZ [https://sfzformat.com] and Decent Sampler
+//! [https://www.decentsamples.com/docs/format-documentation.html]
+//! presets. Each slice becomes a single region mapped to one MIDI key,
+//! counting up from the root note.
+use crate::{LoopType, OTData, Slice};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Highest MIDI note number.
+pub const MAX_KEY: u8 = 127;
+
+#[derive(Debug, PartialEq)]
+pub struct Instrument {
+  sample: PathBuf,
+  root_key: u8,
+  loop_type: LoopType,
+  slices: Vec<Slice>,
+}
+
+impl Instrument {
+  /// Create a new instrument. SAMPLE is the path written into the
+  /// preset, so it should usually be relative to the preset file.
+  pub fn new<P: AsRef<Path>>(sample: P) -> Self {
+    Instrument {
+      sample: sample.as_ref().to_path_buf(),
+      root_key: 36,
+      loop_type: LoopType::Off,
+      slices: vec![],
+    }
+  }
+  pub fn root_key(mut self, root_key: u8) -> Self {
+    self.root_key = root_key.min(MAX_KEY);
+    self
+  }
+  pub fn loop_type(mut self, loop_type: LoopType) -> Self {
+    self.loop_type = loop_type;
+    self
+  }
+  pub fn slices(mut self, slices: Vec<Slice>) -> Self {
+    self.slices = slices;
+    self
+  }
+
+  /// Build an instrument from a WAV file and its matching .ot file. The
+  /// sample path in the preset is the WAV file name.
+  pub fn from_ot<W: AsRef<Path>, O: AsRef<Path>>(wav: W, ot: O) -> std::io::Result<Self> {
+    let data = OTData::decode(&std::fs::read(ot)?)?;
+    let name = wav.as_ref().file_name().ok_or_else(|| {
+      std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid sample path")
+    })?;
+    Ok(Instrument::new(name)
+       .loop_type(data.loop_type()?)
+       .slices(data.slices().to_vec()))
+  }
+
+  /// Slices paired with the MIDI key they are mapped to. Slices which
+  /// would land above MAX_KEY are dropped.
+  pub fn regions(&self) -> impl Iterator<Item = (u8, &Slice)> {
+    (self.root_key..=MAX_KEY).zip(self.slices.iter())
+  }
+
+  pub fn to_sfz(&self) -> String {
+    let mut sfz = String::new();
+    writeln!(sfz, "<control>").unwrap();
+    writeln!(sfz, "default_path={}", parent_path(&self.sample)).unwrap();
+    writeln!(sfz, "\n<group>").unwrap();
+    writeln!(sfz, "sample={}", file_name(&self.sample)).unwrap();
+    match self.loop_type {
+      LoopType::Off => writeln!(sfz, "loop_mode=no_loop").unwrap(),
+      LoopType::Loop => writeln!(sfz, "loop_mode=loop_continuous").unwrap(),
+      LoopType::PingPong => {
+	writeln!(sfz, "loop_mode=loop_continuous").unwrap();
+	writeln!(sfz, "loop_type=alternate").unwrap();
+      }
+    }
+    for (key, slice) in self.regions() {
+      // sfz end points are inclusive
+      write!(sfz, "\n<region> key={} pitch_keycenter={} offset={} end={}",
+	     key, key, slice.start(), slice.end().saturating_sub(1)).unwrap();
+      if self.loop_type != LoopType::Off {
+	write!(sfz, " loop_start={} loop_end={}",
+	       slice.loop_start().unwrap_or_else(|| slice.start()),
+	       slice.end().saturating_sub(1)).unwrap();
+      }
+      writeln!(sfz).unwrap();
+    }
+    sfz
+  }
+
+  /// Decent Sampler has no ping-pong loop mode, so LoopType::PingPong
+  /// is exported as a forward loop.
+  pub fn to_dspreset(&self) -> String {
+    let mut ds = String::new();
+    let path = escape_xml(&self.sample.to_string_lossy());
+    writeln!(ds, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>").unwrap();
+    writeln!(ds, "<DecentSampler minVersion=\"1.0.0\">").unwrap();
+    writeln!(ds, "  <groups>").unwrap();
+    writeln!(ds, "    <group>").unwrap();
+    for (key, slice) in self.regions() {
+      write!(ds, "      <sample path=\"{}\" rootNote=\"{}\" loNote=\"{}\" hiNote=\"{}\" start=\"{}\" end=\"{}\"",
+	     path, key, key, key, slice.start(), slice.end()).unwrap();
+      if self.loop_type != LoopType::Off {
+	write!(ds, " loopEnabled=\"true\" loopStart=\"{}\" loopEnd=\"{}\"",
+	       slice.loop_start().unwrap_or_else(|| slice.start()),
+	       slice.end()).unwrap();
+      }
+      writeln!(ds, "/>").unwrap();
+    }
+    writeln!(ds, "    </group>").unwrap();
+    writeln!(ds, "  </groups>").unwrap();
+    writeln!(ds, "</DecentSampler>").unwrap();
+    ds
+  }
+
+  /// Write an SFZ file to PATH. Existing files are not overwritten.
+  pub fn write_sfz<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+    write_new(path.as_ref(), self.to_sfz().as_bytes())
+  }
+
+  /// Write a Decent Sampler preset to PATH. Existing files are not
+  /// overwritten.
+  pub fn write_dspreset<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+    write_new(path.as_ref(), self.to_dspreset().as_bytes())
+  }
+}
+
+fn write_new(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+  if !path.exists() {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(bytes)
+  } else {
+    Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("file already exists: {}", path.display())))
+  }
+}
+
+fn parent_path(path: &Path) -> String {
+  match path.parent().map(|p| p.to_string_lossy()) {
+    Some(p) if !p.is_empty() => format!("{}/", p),
+    _ => String::new(),
+  }
+}
+
+fn file_name(path: &Path) -> String {
+  path.file_name().map(|p| p.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+fn escape_xml(s: &str) -> String {
+  s.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn regions() {
+    let inst = Instrument::new("kits/chain & co.wav")
+      .root_key(126)
+      .loop_type(LoopType::PingPong)
+      .slices(vec![Slice::new(0, 100), Slice::new(100, 250).loop_point(150), Slice::new(250, 300)]);
+    // only two keys left above the root
+    assert_eq!(inst.regions().count(), 2);
+
+    let sfz = inst.to_sfz();
+    assert!(sfz.contains("default_path=kits/"));
+    assert!(sfz.contains("loop_type=alternate"));
+    assert!(sfz.contains("<region> key=126 pitch_keycenter=126 offset=0 end=99 loop_start=0 loop_end=99"));
+    assert!(sfz.contains("<region> key=127 pitch_keycenter=127 offset=100 end=249 loop_start=150 loop_end=249"));
+
+    let ds = inst.to_dspreset();
+    assert!(ds.contains("path=\"kits/chain &amp; co.wav\""));
+    assert!(ds.contains("start=\"100\" end=\"250\" loopEnabled=\"true\" loopStart=\"150\" loopEnd=\"250\""));
+
+    // truncated and foreign .ot files are errors
+    let ot = std::env::temp_dir().join("ot_sampler_regions.ot");
+    for bytes in [OTData::new().to_vec()[..100].to_vec(), vec![0; crate::FILE_SIZE]] {
+      std::fs::write(&ot, bytes).unwrap();
+      assert!(Instrument::from_ot("a.wav", &ot).is_err());
+    }
+    std::fs::remove_file(&ot).unwrap();
+  }
+}